@@ -0,0 +1,159 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// How many recent heartbeats the estimate is based on.
+const WINDOW: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// Server clock minus local clock, in microseconds.
+    offset: f64,
+    delay: Duration,
+}
+
+/// Estimate of the server clock relative to ours, kept NTP-style from the
+/// heartbeat round trips.
+///
+/// Of the recent samples, the one with the lowest round trip is trusted the
+/// most, since it leaves the least room for asymmetric delays; jitter is how
+/// far the others stray from it.
+#[derive(Debug, Clone)]
+pub struct ClockSync {
+    epoch: Instant,
+    samples: VecDeque<Sample>,
+    best: Option<Sample>,
+    jitter: Duration,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            samples: VecDeque::with_capacity(WINDOW),
+            best: None,
+            jitter: Duration::ZERO,
+        }
+    }
+
+    fn micros_since_epoch(&self, instant: Instant) -> f64 {
+        match instant.checked_duration_since(self.epoch) {
+            Some(it) => it.as_micros() as f64,
+            None => -((self.epoch - instant).as_micros() as f64),
+        }
+    }
+
+    /// Record a heartbeat sent at `sent` and answered at `received`, the
+    /// server reporting `server_time` in between.
+    pub fn add_sample(&mut self, sent: Instant, received: Instant, server_time: u64) {
+        let delay = received.saturating_duration_since(sent);
+        let local = self.micros_since_epoch(sent + delay / 2);
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample {
+            offset: server_time as f64 - local,
+            delay,
+        });
+
+        let best = *self.samples.iter().min_by_key(|it| it.delay).unwrap();
+        let variance = self
+            .samples
+            .iter()
+            .map(|it| (it.offset - best.offset).powi(2))
+            .sum::<f64>()
+            / self.samples.len() as f64;
+        self.best = Some(best);
+        self.jitter = Duration::from_micros(variance.sqrt() as u64);
+    }
+
+    /// Whether any heartbeat has been answered yet.
+    pub fn is_synced(&self) -> bool {
+        self.best.is_some()
+    }
+
+    /// Server clock minus local clock, in microseconds.
+    pub fn offset(&self) -> Option<f64> {
+        self.best.map(|it| it.offset)
+    }
+
+    /// Round trip of the sample the estimate is based on.
+    pub fn delay(&self) -> Option<Duration> {
+        self.best.map(|it| it.delay)
+    }
+
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// The local instant at which the server clock reads `time`.
+    pub fn to_local(&self, time: u64) -> Option<Instant> {
+        let micros = time as f64 - self.offset()?;
+        Some(if micros >= 0. {
+            self.epoch + Duration::from_micros(micros as u64)
+        } else {
+            self.epoch
+                .checked_sub(Duration::from_micros(-micros as u64))
+                .unwrap_or(self.epoch)
+        })
+    }
+
+    /// What the server clock reads at `instant`.
+    pub fn to_server(&self, instant: Instant) -> Option<u64> {
+        Some((self.micros_since_epoch(instant) + self.offset()?).max(0.) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A heartbeat sent `at` ms after the epoch, taking `delay` ms, while the
+    /// server clock was `offset` µs ahead.
+    fn sample(clock: &mut ClockSync, at: u64, delay: u64, offset: u64) {
+        let sent = clock.epoch + Duration::from_millis(at);
+        let received = sent + Duration::from_millis(delay);
+        let server_time = at * 1000 + delay * 500 + offset;
+        clock.add_sample(sent, received, server_time);
+    }
+
+    #[test]
+    fn lowest_delay_wins() {
+        let mut clock = ClockSync::new();
+        assert!(!clock.is_synced());
+        sample(&mut clock, 0, 40, 6000);
+        sample(&mut clock, 100, 10, 5000);
+        sample(&mut clock, 200, 30, 4000);
+
+        assert_eq!(clock.offset(), Some(5000.));
+        assert_eq!(clock.delay(), Some(Duration::from_millis(10)));
+        // sqrt((1000² + 0 + 1000²) / 3)
+        assert_eq!(clock.jitter(), Duration::from_micros(816));
+
+        let instant = clock.epoch + Duration::from_secs(1);
+        assert_eq!(clock.to_server(instant), Some(1_005_000));
+        assert_eq!(clock.to_local(1_005_000), Some(instant));
+    }
+
+    #[test]
+    fn best_sample_leaves_window() {
+        let mut clock = ClockSync::new();
+        sample(&mut clock, 0, 10, 5000);
+        for i in 1..WINDOW as u64 {
+            sample(&mut clock, i * 100, 20, 7000);
+        }
+        assert_eq!(clock.offset(), Some(5000.));
+
+        sample(&mut clock, WINDOW as u64 * 100, 20, 7000);
+        assert_eq!(clock.offset(), Some(7000.));
+        assert_eq!(clock.delay(), Some(Duration::from_millis(20)));
+        assert_eq!(clock.jitter(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,64 @@
+use crate::Client;
+use phira_mp_common::{JudgeEvent, Message, RoomState, TouchFrame, UserInfo};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::broadcast;
+use tokio_stream::{
+    Stream, StreamExt,
+    wrappers::{BroadcastStream, errors::BroadcastStreamRecvError},
+};
+use tracing::warn;
+
+/// Something that happened to a [`Client`], see [`Client::subscribe`].
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    Message(Message),
+    /// The room moved to another state.
+    StateChanged(RoomState),
+    /// We became the host, or stopped being it.
+    HostChanged(bool),
+    UserJoined(UserInfo),
+    UserLeft(i32),
+    Touches {
+        player: i32,
+        frames: Arc<Vec<TouchFrame>>,
+    },
+    Judges {
+        player: i32,
+        judges: Arc<Vec<JudgeEvent>>,
+    },
+    /// A heartbeat round trip completed.
+    Latency(Duration),
+
+    /// The connection was lost.
+    Disconnected,
+    /// Redialing the server, `attempt` counting from 1.
+    Reconnecting {
+        attempt: u32,
+    },
+    /// The connection is back and the session restored.
+    Reconnected,
+    /// Gave up reconnecting.
+    ReconnectFailed,
+}
+
+impl Client {
+    /// Receive every [`ClientEvent`] from now on.
+    ///
+    /// Events are kept in a bounded buffer; a receiver that falls behind
+    /// loses the oldest ones.
+    pub fn subscribe(&self) -> broadcast::Receiver<ClientEvent> {
+        self.state.events.subscribe()
+    }
+
+    /// Same as [`Client::subscribe`], as a [`Stream`]. Lost events are
+    /// skipped.
+    pub fn events(&self) -> impl Stream<Item = ClientEvent> + use<> {
+        BroadcastStream::new(self.subscribe()).filter_map(|it| match it {
+            Ok(event) => Some(event),
+            Err(BroadcastStreamRecvError::Lagged(count)) => {
+                warn!("event stream lagged, {count} events lost");
+                None
+            }
+        })
+    }
+}
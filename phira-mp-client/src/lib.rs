@@ -1,28 +1,41 @@
-use anyhow::{Context, Error, Result};
+mod clock;
+pub use clock::*;
+
+mod event;
+pub use event::*;
+
+mod reconnect;
+pub use reconnect::*;
+
+use anyhow::{Context, Error, Result, anyhow, bail};
 use dashmap::DashMap;
 use phira_mp_common::{
-    ClientCommand, ClientRoomState, HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT, JoinRoomResponse,
-    JudgeEvent, Message, RoomId, RoomState, ServerCommand, Stream, TouchFrame, UserInfo,
+    Capabilities, ClientCommand, ClientRoomState, HEARTBEAT_INTERVAL, HEARTBEAT_TIMEOUT, Handshake,
+    Hello, JudgeEvent, Message, PlayRecord, Protocol, RankingMetric, ReadyTimeoutAction, RoomId,
+    RoomList, RoomState, ServerCommand, Stream, TouchFrame, UserInfo,
 };
 use std::{
+    net::SocketAddr,
     sync::{
         Arc,
-        atomic::{AtomicU8, Ordering},
+        atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering},
     },
     time::{Duration, Instant},
 };
 use tokio::{
     net::TcpStream,
-    sync::{Mutex, Notify, RwLock, oneshot},
+    sync::{Mutex, RwLock, broadcast, oneshot},
     task::JoinHandle,
     time,
 };
-use tracing::{error, trace, warn};
+use tracing::{trace, warn};
 
-type Callback<T> = Mutex<Option<oneshot::Sender<T>>>;
-type RCallback<T, E = String> = Mutex<Option<oneshot::Sender<Result<T, E>>>>;
+type ClientStream = Stream<ClientCommand, ServerCommand>;
+/// The current connection, replaced when reconnecting.
+type StreamSlot = Arc<std::sync::RwLock<Arc<ClientStream>>>;
 
 pub const TIMEOUT: Duration = Duration::from_secs(7);
+const EVENT_CAPACITY: usize = 256;
 
 pub struct LivePlayer {
     pub touch_frames: Mutex<Vec<TouchFrame>>,
@@ -46,30 +59,50 @@ impl LivePlayer {
 
 struct State {
     delay: Mutex<Option<Duration>>,
-    ping_notify: Notify,
+    clock: Mutex<ClockSync>,
+    start_at: Mutex<Option<Instant>>,
 
     me: RwLock<Option<UserInfo>>,
     room: RwLock<Option<ClientRoomState>>,
+    ready_deadline: Mutex<Option<Instant>>,
+    /// Last token we authenticated with, to resume the session.
+    token: Mutex<Option<String>>,
 
-    cb_authenticate: RCallback<(UserInfo, Option<ClientRoomState>)>,
-    cb_chat: RCallback<()>,
-    cb_create_room: RCallback<()>,
-    cb_join_room: RCallback<JoinRoomResponse>,
-    cb_leave_room: RCallback<()>,
-    cb_lock_room: RCallback<()>,
-    cb_cycle_room: RCallback<()>,
-    cb_select_chart: RCallback<()>,
-    cb_request_start: RCallback<()>,
-    cb_ready: RCallback<()>,
-    cb_cancel_ready: RCallback<()>,
-    cb_played: RCallback<()>,
-    cb_abort: RCallback<()>,
+    events: broadcast::Sender<ClientEvent>,
+    /// Whether [`ClientEvent::Disconnected`] was sent for this connection.
+    disconnected: AtomicBool,
+
+    /// Requests awaiting a response, by id.
+    pending: DashMap<u32, oneshot::Sender<ServerCommand>>,
+    next_request_id: AtomicU32,
 
     live_players: DashMap<i32, Arc<LivePlayer>>,
     messages: Mutex<Vec<Message>>,
 }
 
 impl State {
+    fn emit(&self, event: ClientEvent) {
+        // nobody listening is fine
+        let _ = self.events.send(event);
+    }
+
+    fn on_disconnected(&self) {
+        // nothing will answer these anymore
+        self.pending.clear();
+        if !self.disconnected.swap(true, Ordering::SeqCst) {
+            self.emit(ClientEvent::Disconnected);
+        }
+    }
+
+    async fn on_authenticated(&self, me: UserInfo, room: Option<ClientRoomState>) {
+        *self.me.write().await = Some(me);
+        *self.ready_deadline.lock().await = room
+            .as_ref()
+            .and_then(|it| it.ready_countdown)
+            .and_then(deadline_after);
+        *self.room.write().await = room;
+    }
+
     pub fn live_player(&self, player: i32) -> Arc<LivePlayer> {
         Arc::clone(
             &self
@@ -80,54 +113,145 @@ impl State {
     }
 }
 
+/// When a countdown of `seconds` sent by the server runs out, unless the
+/// server sent nonsense.
+fn deadline_after(seconds: f32) -> Option<Instant> {
+    let deadline = Duration::try_from_secs_f32(seconds)
+        .ok()
+        .and_then(|it| Instant::now().checked_add(it));
+    if deadline.is_none() {
+        warn!("ignoring invalid countdown of {seconds} seconds");
+    }
+    deadline
+}
+
+async fn connect(state: &Arc<State>, stream: TcpStream) -> Result<ClientStream> {
+    let stream = Stream::new(
+        Handshake::Offer(Hello::default()),
+        stream,
+        Box::new({
+            let state = Arc::clone(state);
+            move |_send_tx, cmd| process(Arc::clone(&state), cmd)
+        }),
+    )
+    .await?;
+    // every call is a request
+    if !stream.protocol().supports(Capabilities::REQUEST_IDS) {
+        bail!("server doesn't support request ids");
+    }
+    state.disconnected.store(false, Ordering::SeqCst);
+    Ok(stream)
+}
+
+/// Removes its request from the pending table when dropped, so that requests
+/// timing out or being cancelled don't linger.
+struct PendingGuard<'a> {
+    state: &'a State,
+    id: u32,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.state.pending.remove(&self.id);
+    }
+}
+
+async fn request(
+    state: &State,
+    stream: &ClientStream,
+    command: ClientCommand,
+) -> Result<ServerCommand> {
+    let id = state.next_request_id.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = oneshot::channel();
+    // register before sending, the response may beat `send` returning
+    state.pending.insert(id, tx);
+    let _guard = PendingGuard { state, id };
+    stream
+        .send(ClientCommand::Request {
+            id,
+            command: Box::new(command),
+        })
+        .await?;
+    match time::timeout(TIMEOUT, rx)
+        .await
+        .context("timeout")?
+        .context("request cancelled")?
+    {
+        ServerCommand::Error(err) => Err(Error::msg(err)),
+        resp => Ok(resp),
+    }
+}
+
+/// One heartbeat round trip, sampling the server clock. Sent as a request so
+/// that overlapping pings can't take each other's answers.
+async fn ping(state: &State, stream: &ClientStream) -> Result<Duration> {
+    let start = Instant::now();
+    let resp = time::timeout(
+        HEARTBEAT_TIMEOUT,
+        request(state, stream, ClientCommand::Ping),
+    )
+    .await
+    .context("heartbeat timeout")??;
+    let received = Instant::now();
+    let ServerCommand::Pong { time } = resp else {
+        return Err(anyhow!("unexpected response: {resp:?}"));
+    };
+    if stream.protocol().supports(Capabilities::CLOCK_SYNC) {
+        state.clock.lock().await.add_sample(start, received, time);
+    }
+    let delay = received - start;
+    *state.delay.lock().await = Some(delay);
+    Ok(delay)
+}
+
+/// Send a request and unwrap its `ServerCommand::$reply` response.
+macro_rules! rcall {
+    ($this:expr, $command:expr, $reply:ident) => {
+        match $this.request($command).await? {
+            ServerCommand::$reply(res) => res.map_err(Error::msg),
+            other => Err(anyhow!("unexpected response: {other:?}")),
+        }
+    };
+}
+
 pub struct Client {
     state: Arc<State>,
 
-    stream: Arc<Stream<ClientCommand, ServerCommand>>,
+    addr: SocketAddr,
+    stream: StreamSlot,
 
     ping_fail_count: Arc<AtomicU8>,
     ping_task_handle: JoinHandle<()>,
+    reconnect_task_handle: std::sync::Mutex<Option<JoinHandle<()>>>,
 }
 
 impl Client {
+    /// Connect over `stream`. The server must speak the handshake: servers
+    /// that predate it are not supported.
     pub async fn new(stream: TcpStream) -> Result<Self> {
         stream.set_nodelay(true)?;
+        let addr = stream.peer_addr()?;
 
         let state = Arc::new(State {
             delay: Mutex::default(),
-            ping_notify: Notify::new(),
+            clock: Mutex::default(),
+            start_at: Mutex::default(),
 
             me: RwLock::default(),
             room: RwLock::default(),
+            ready_deadline: Mutex::default(),
+            token: Mutex::default(),
+
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            disconnected: AtomicBool::new(false),
 
-            cb_authenticate: Callback::default(),
-            cb_chat: Callback::default(),
-            cb_create_room: Callback::default(),
-            cb_join_room: Callback::default(),
-            cb_leave_room: Callback::default(),
-            cb_lock_room: Callback::default(),
-            cb_cycle_room: Callback::default(),
-            cb_select_chart: Callback::default(),
-            cb_request_start: Callback::default(),
-            cb_ready: Callback::default(),
-            cb_cancel_ready: Callback::default(),
-            cb_played: Callback::default(),
-            cb_abort: Callback::default(),
+            pending: DashMap::new(),
+            next_request_id: AtomicU32::new(0),
 
             live_players: DashMap::new(),
             messages: Mutex::default(),
         });
-        let stream = Arc::new(
-            Stream::new(
-                Some(1),
-                stream,
-                Box::new({
-                    let state = Arc::clone(&state);
-                    move |_send_tx, cmd| process(Arc::clone(&state), cmd)
-                }),
-            )
-            .await?,
-        );
+        let stream: StreamSlot = Arc::new(Arc::new(connect(&state, stream).await?).into());
 
         let ping_fail_count = Arc::new(AtomicU8::default());
         let ping_task_handle = tokio::spawn({
@@ -138,21 +262,21 @@ impl Client {
                 loop {
                     time::sleep(HEARTBEAT_INTERVAL).await;
 
-                    let start = Instant::now();
-                    if let Err(err) = stream.send(ClientCommand::Ping).await {
-                        error!("failed to send heartbeat: {err:?}");
-                    } else if time::timeout(HEARTBEAT_TIMEOUT, state.ping_notify.notified())
-                        .await
-                        .is_err()
-                    {
-                        warn!("heartbeat timeout");
-                        ping_fail_count.fetch_add(1, Ordering::Relaxed);
-                    } else {
-                        ping_fail_count.store(0, Ordering::SeqCst);
+                    let stream = Arc::clone(&*stream.read().unwrap());
+                    if stream.is_closed() {
+                        state.on_disconnected();
+                    }
+                    match ping(&state, &stream).await {
+                        Ok(delay) => {
+                            ping_fail_count.store(0, Ordering::SeqCst);
+                            state.emit(ClientEvent::Latency(delay));
+                            trace!("sent heartbeat, delay: {delay:?}");
+                        }
+                        Err(err) => {
+                            warn!("heartbeat failed: {err:?}");
+                            ping_fail_count.fetch_add(1, Ordering::Relaxed);
+                        }
                     }
-                    let delay = start.elapsed();
-                    *state.delay.lock().await = Some(delay);
-                    trace!("sent heartbeat, delay: {delay:?}");
                 }
             }
         });
@@ -160,13 +284,40 @@ impl Client {
         Ok(Self {
             state,
 
+            addr,
             stream,
 
             ping_fail_count,
             ping_task_handle,
+            reconnect_task_handle: std::sync::Mutex::default(),
         })
     }
 
+    fn stream(&self) -> Arc<ClientStream> {
+        Arc::clone(&*self.stream.read().unwrap())
+    }
+
+    /// What the current connection negotiated with the server.
+    pub fn protocol(&self) -> Protocol {
+        self.stream().protocol()
+    }
+
+    /// Opt in to redialing the server whenever the connection is lost, then
+    /// authenticating again and restoring [`Client::me`] and the room state.
+    /// Progress is reported as [`ClientEvent`]s.
+    pub fn enable_reconnect(&self, config: ReconnectConfig) {
+        let handle = tokio::spawn(reconnect::supervise(
+            Arc::clone(&self.state),
+            Arc::clone(&self.stream),
+            self.addr,
+            Arc::clone(&self.ping_fail_count),
+            config,
+        ));
+        if let Some(old) = self.reconnect_task_handle.lock().unwrap().replace(handle) {
+            old.abort();
+        }
+    }
+
     pub fn me(&self) -> Option<UserInfo> {
         self.state.me.blocking_read().clone()
     }
@@ -223,64 +374,107 @@ impl Client {
             .map(|it| it.is_ready)
     }
 
-    pub async fn ping(&self) -> Result<Duration> {
-        let start = Instant::now();
-        self.stream.send(ClientCommand::Ping).await?;
-        time::timeout(HEARTBEAT_TIMEOUT, self.state.ping_notify.notified())
+    /// Time left to get ready, while waiting for ready.
+    pub fn blocking_ready_countdown(&self) -> Option<Duration> {
+        self.state
+            .ready_deadline
+            .blocking_lock()
+            .map(|it| it.saturating_duration_since(Instant::now()))
+    }
+
+    pub async fn ready_countdown(&self) -> Option<Duration> {
+        self.state
+            .ready_deadline
+            .lock()
             .await
-            .context("heartbeat timeout")?;
-        let delay = start.elapsed();
-        *self.state.delay.lock().await = Some(delay);
-        Ok(delay)
+            .map(|it| it.saturating_duration_since(Instant::now()))
+    }
+
+    /// Snapshot of the server clock estimate.
+    pub fn blocking_clock(&self) -> ClockSync {
+        self.state.clock.blocking_lock().clone()
+    }
+
+    pub async fn clock(&self) -> ClockSync {
+        self.state.clock.lock().await.clone()
+    }
+
+    /// The local instant at which the server clock reads `time`, such as a
+    /// [`Message::StartAt`] timestamp.
+    pub async fn server_to_local(&self, time: u64) -> Option<Instant> {
+        self.state.clock.lock().await.to_local(time)
+    }
+
+    /// When the current game starts, if the server clock is known yet.
+    pub fn blocking_start_at(&self) -> Option<Instant> {
+        *self.state.start_at.blocking_lock()
+    }
+
+    pub async fn start_at(&self) -> Option<Instant> {
+        *self.state.start_at.lock().await
+    }
+
+    pub async fn ping(&self) -> Result<Duration> {
+        ping(&self.state, &self.stream()).await
     }
 
     pub fn delay(&self) -> Option<Duration> {
         *self.state.delay.blocking_lock()
     }
 
-    async fn rcall<R>(&self, payload: ClientCommand, cb: &RCallback<R>) -> Result<R> {
-        self.stream.send(payload).await?;
-        let (tx, rx) = oneshot::channel();
-        *cb.lock().await = Some(tx);
-        time::timeout(TIMEOUT, rx)
-            .await
-            .context("timeout")??
-            .map_err(Error::msg)
+    #[inline]
+    async fn request(&self, command: ClientCommand) -> Result<ServerCommand> {
+        request(&self.state, &self.stream(), command).await
     }
 
     #[inline]
     pub async fn authenticate(&self, token: impl Into<String>) -> Result<()> {
-        let (me, room) = self
-            .rcall(
-                ClientCommand::Authenticate {
-                    token: token.into().try_into()?,
-                },
-                &self.state.cb_authenticate,
-            )
-            .await?;
-        *self.state.me.write().await = Some(me);
-        *self.state.room.write().await = room;
+        let token = token.into();
+        let (me, room) = rcall!(
+            self,
+            ClientCommand::Authenticate {
+                token: token.clone().try_into()?,
+            },
+            Authenticate
+        )?;
+        *self.state.token.lock().await = Some(token);
+        self.state.on_authenticated(me, room).await;
         Ok(())
     }
 
     #[inline]
     pub async fn chat(&self, message: String) -> Result<()> {
-        self.rcall(
+        rcall!(
+            self,
             ClientCommand::Chat {
                 message: message.try_into()?,
             },
-            &self.state.cb_chat,
+            Chat
         )
-        .await
     }
 
     #[inline]
     pub async fn create_room(&self, id: RoomId) -> Result<()> {
-        self.rcall(
-            ClientCommand::CreateRoom { id: id.clone() },
-            &self.state.cb_create_room,
-        )
-        .await?;
+        self.create_room_with_password(id, None, false).await
+    }
+
+    /// Create a room that requires `password` to join. Private rooms are
+    /// hidden from [`Client::list_rooms`].
+    pub async fn create_room_with_password(
+        &self,
+        id: RoomId,
+        password: Option<String>,
+        private: bool,
+    ) -> Result<()> {
+        rcall!(
+            self,
+            ClientCommand::CreateRoom {
+                id: id.clone(),
+                password: password.map(TryInto::try_into).transpose()?,
+                private,
+            },
+            CreateRoom
+        )?;
         let me = self.state.me.read().await.clone().unwrap();
         *self.state.room.write().await = Some(ClientRoomState {
             id,
@@ -291,21 +485,31 @@ impl Client {
             is_host: true,
             is_ready: false,
             users: std::iter::once((me.id, me)).collect(),
+            ready_countdown: None,
         });
         Ok(())
     }
 
     #[inline]
     pub async fn join_room(&self, id: RoomId, monitor: bool) -> Result<()> {
-        let resp = self
-            .rcall(
-                ClientCommand::JoinRoom {
-                    id: id.clone(),
-                    monitor,
-                },
-                &self.state.cb_join_room,
-            )
-            .await?;
+        self.join_room_with_password(id, monitor, None).await
+    }
+
+    pub async fn join_room_with_password(
+        &self,
+        id: RoomId,
+        monitor: bool,
+        password: Option<String>,
+    ) -> Result<()> {
+        let resp = rcall!(
+            self,
+            ClientCommand::JoinRoom {
+                id: id.clone(),
+                monitor,
+                password: password.map(TryInto::try_into).transpose()?,
+            },
+            JoinRoom
+        )?;
         *self.state.room.write().await = Some(ClientRoomState {
             id,
             state: resp.state,
@@ -315,75 +519,123 @@ impl Client {
             is_host: false,
             is_ready: false,
             users: resp.users.into_iter().map(|it| (it.id, it)).collect(),
+            ready_countdown: None,
         });
         Ok(())
     }
 
     #[inline]
     pub async fn leave_room(&self) -> Result<()> {
-        self.rcall(ClientCommand::LeaveRoom, &self.state.cb_leave_room)
-            .await?;
+        rcall!(self, ClientCommand::LeaveRoom, LeaveRoom)?;
         *self.state.room.write().await = None;
         Ok(())
     }
 
     #[inline]
     pub async fn lock_room(&self, lock: bool) -> Result<()> {
-        self.rcall(ClientCommand::LockRoom { lock }, &self.state.cb_lock_room)
-            .await
+        rcall!(self, ClientCommand::LockRoom { lock }, LockRoom)
     }
 
     #[inline]
     pub async fn cycle_room(&self, cycle: bool) -> Result<()> {
-        self.rcall(
-            ClientCommand::CycleRoom { cycle },
-            &self.state.cb_cycle_room,
-        )
-        .await
+        rcall!(self, ClientCommand::CycleRoom { cycle }, CycleRoom)
     }
 
     #[inline]
     pub async fn select_chart(&self, id: i32) -> Result<()> {
-        self.rcall(
-            ClientCommand::SelectChart { id },
-            &self.state.cb_select_chart,
-        )
-        .await
+        rcall!(self, ClientCommand::SelectChart { id }, SelectChart)
     }
 
     #[inline]
     pub async fn request_start(&self) -> Result<()> {
-        self.rcall(ClientCommand::RequestStart, &self.state.cb_request_start)
-            .await?;
+        rcall!(self, ClientCommand::RequestStart, RequestStart)?;
         self.state.room.write().await.as_mut().unwrap().is_ready = true;
         Ok(())
     }
 
     #[inline]
     pub async fn ready(&self) -> Result<()> {
-        self.rcall(ClientCommand::Ready, &self.state.cb_ready)
-            .await?;
+        rcall!(self, ClientCommand::Ready, Ready)?;
         self.state.room.write().await.as_mut().unwrap().is_ready = true;
         Ok(())
     }
 
     #[inline]
     pub async fn cancel_ready(&self) -> Result<()> {
-        self.rcall(ClientCommand::CancelReady, &self.state.cb_cancel_ready)
-            .await?;
+        rcall!(self, ClientCommand::CancelReady, CancelReady)?;
         self.state.room.write().await.as_mut().unwrap().is_ready = false;
         Ok(())
     }
 
     #[inline]
     pub async fn played(&self, id: i32) -> Result<()> {
-        self.rcall(ClientCommand::Played { id }, &self.state.cb_played)
-            .await
+        rcall!(self, ClientCommand::Played { id }, Played)
+    }
+
+    /// Upload the record directly, for servers that don't fetch records from
+    /// the Phira API.
+    #[inline]
+    pub async fn submit_record(&self, record: PlayRecord) -> Result<()> {
+        rcall!(self, ClientCommand::SubmitRecord { record }, Played)
     }
 
     #[inline]
     pub async fn abort(&self) -> Result<()> {
-        self.rcall(ClientCommand::Abort, &self.state.cb_abort).await
+        rcall!(self, ClientCommand::Abort, Abort)
+    }
+
+    /// List public rooms, [`phira_mp_common::ROOM_LIST_PAGE_SIZE`] per page.
+    #[inline]
+    pub async fn list_rooms(&self, page: u32) -> Result<RoomList> {
+        rcall!(self, ClientCommand::ListRooms { page }, ListRooms)
+    }
+
+    /// Change or clear (with `None`) the room password. Host only.
+    #[inline]
+    pub async fn set_password(&self, password: Option<String>) -> Result<()> {
+        rcall!(
+            self,
+            ClientCommand::SetPassword {
+                password: password.map(TryInto::try_into).transpose()?,
+            },
+            SetPassword
+        )
+    }
+
+    #[inline]
+    pub async fn kick_user(&self, user: i32) -> Result<()> {
+        rcall!(self, ClientCommand::KickUser { user }, KickUser)
+    }
+
+    /// Kick `user` and keep them out until the room is dropped.
+    #[inline]
+    pub async fn ban_user(&self, user: i32) -> Result<()> {
+        rcall!(self, ClientCommand::BanUser { user }, BanUser)
+    }
+
+    #[inline]
+    pub async fn transfer_host(&self, user: i32) -> Result<()> {
+        rcall!(self, ClientCommand::TransferHost { user }, TransferHost)
+    }
+
+    /// Choose how players are ranked in [`Message::GameResults`]. Host only.
+    #[inline]
+    pub async fn set_ranking_metric(&self, metric: RankingMetric) -> Result<()> {
+        rcall!(
+            self,
+            ClientCommand::SetRankingMetric { metric },
+            SetRankingMetric
+        )
+    }
+
+    /// Choose what happens when not everyone gets ready in time. Host only.
+    #[inline]
+    pub async fn set_ready_timeout_action(&self, action: ReadyTimeoutAction) -> Result<()> {
+        rcall!(
+            self,
+            ClientCommand::SetReadyTimeoutAction { action },
+            SetReadyTimeoutAction
+        )
     }
 
     pub fn ping_fail_count(&self) -> u8 {
@@ -391,11 +643,11 @@ impl Client {
     }
 
     pub async fn send(&self, payload: ClientCommand) -> Result<()> {
-        self.stream.send(payload).await
+        self.stream().send(payload).await
     }
 
     pub fn blocking_send(&self, payload: ClientCommand) -> Result<()> {
-        self.stream.blocking_send(payload)
+        self.stream().blocking_send(payload)
     }
 
     #[inline]
@@ -407,23 +659,16 @@ impl Client {
 impl Drop for Client {
     fn drop(&mut self) {
         self.ping_task_handle.abort();
+        if let Some(handle) = self.reconnect_task_handle.lock().unwrap().take() {
+            handle.abort();
+        }
     }
 }
 
 async fn process(state: Arc<State>, cmd: ServerCommand) {
-    async fn cb<T>(cb: &Callback<T>, res: T) {
-        let _ = cb.lock().await.take().unwrap().send(res);
-    }
     match cmd {
-        ServerCommand::Pong => {
-            state.ping_notify.notify_one();
-        }
-        ServerCommand::Authenticate(res) => {
-            cb(&state.cb_authenticate, res).await;
-        }
-        ServerCommand::Chat(res) => {
-            cb(&state.cb_chat, res).await;
-        }
+        // answered through `ping`
+        ServerCommand::Pong { .. } => {}
         ServerCommand::Touches { player, frames } => {
             state
                 .live_player(player)
@@ -431,6 +676,7 @@ async fn process(state: Arc<State>, cmd: ServerCommand) {
                 .lock()
                 .await
                 .extend(frames.iter().cloned());
+            state.emit(ClientEvent::Touches { player, frames });
         }
         ServerCommand::Judges { player, judges } => {
             state
@@ -439,6 +685,7 @@ async fn process(state: Arc<State>, cmd: ServerCommand) {
                 .lock()
                 .await
                 .extend(judges.iter().cloned());
+            state.emit(ClientEvent::Judges { player, judges });
         }
         ServerCommand::Message(msg) => {
             match msg {
@@ -457,60 +704,105 @@ async fn process(state: Arc<State>, cmd: ServerCommand) {
                         .unwrap()
                         .users
                         .remove(&user);
+                    state.emit(ClientEvent::UserLeft(user));
+                }
+                Message::KickUser { user, .. } | Message::BanUser { user, .. } => {
+                    if state
+                        .me
+                        .read()
+                        .await
+                        .as_ref()
+                        .is_some_and(|it| it.id == user)
+                    {
+                        *state.room.write().await = None;
+                    } else {
+                        state
+                            .room
+                            .write()
+                            .await
+                            .as_mut()
+                            .unwrap()
+                            .users
+                            .remove(&user);
+                        state.emit(ClientEvent::UserLeft(user));
+                    }
+                }
+                Message::StartAt { time } => {
+                    *state.start_at.lock().await = state.clock.lock().await.to_local(time);
+                }
+                Message::ReadyCountdown { seconds } => {
+                    *state.ready_deadline.lock().await = deadline_after(seconds);
+                    if let Some(room) = state.room.write().await.as_mut() {
+                        room.ready_countdown = Some(seconds);
+                    }
                 }
                 _ => {}
             }
+            state.emit(ClientEvent::Message(msg.clone()));
             state.messages.lock().await.push(msg);
         }
         ServerCommand::ChangeState(room) => {
             state.live_players.clear();
-            let mut guard = state.room.write().await;
-            let state = guard.as_mut().unwrap();
-            state.state = room;
-            state.is_ready = state.is_host;
+            let waiting = matches!(room, RoomState::WaitingForReady);
+            if !waiting {
+                *state.ready_deadline.lock().await = None;
+            }
+            if !matches!(room, RoomState::Playing) {
+                *state.start_at.lock().await = None;
+            }
+            {
+                let mut guard = state.room.write().await;
+                let state = guard.as_mut().unwrap();
+                state.state = room;
+                state.is_ready = state.is_host;
+                if !waiting {
+                    state.ready_countdown = None;
+                }
+            }
+            state.emit(ClientEvent::StateChanged(room));
         }
         ServerCommand::ChangeHost(me_is_host) => {
             state.room.write().await.as_mut().unwrap().is_host = me_is_host;
+            state.emit(ClientEvent::HostChanged(me_is_host));
         }
 
-        ServerCommand::CreateRoom(res) => {
-            cb(&state.cb_create_room, res).await;
-        }
-        ServerCommand::JoinRoom(res) => {
-            cb(&state.cb_join_room, res).await;
-        }
         ServerCommand::OnJoinRoom(user) => {
             if let Some(room) = state.room.write().await.as_mut() {
                 room.live |= user.monitor;
-                room.users.insert(user.id, user);
+                room.users.insert(user.id, user.clone());
             }
+            state.emit(ClientEvent::UserJoined(user));
         }
-        ServerCommand::LeaveRoom(res) => {
-            cb(&state.cb_leave_room, res).await;
-        }
-        ServerCommand::LockRoom(res) => {
-            cb(&state.cb_lock_room, res).await;
-        }
-        ServerCommand::CycleRoom(res) => {
-            cb(&state.cb_cycle_room, res).await;
-        }
-        ServerCommand::SelectChart(res) => {
-            cb(&state.cb_select_chart, res).await;
-        }
-        ServerCommand::RequestStart(res) => {
-            cb(&state.cb_request_start, res).await;
-        }
-        ServerCommand::Ready(res) => {
-            cb(&state.cb_ready, res).await;
-        }
-        ServerCommand::CancelReady(res) => {
-            cb(&state.cb_cancel_ready, res).await;
-        }
-        ServerCommand::Played(res) => {
-            cb(&state.cb_played, res).await;
-        }
-        ServerCommand::Abort(res) => {
-            cb(&state.cb_abort, res).await;
+
+        ServerCommand::Response { id, response } => match state.pending.remove(&id) {
+            Some((_, tx)) => {
+                let _ = tx.send(*response);
+            }
+            None => warn!("response to unknown request {id}, cancelled or timed out"),
+        },
+        // replies always come wrapped in a `Response`, as we only send requests
+        cmd @ (ServerCommand::Authenticate(_)
+        | ServerCommand::Chat(_)
+        | ServerCommand::CreateRoom(_)
+        | ServerCommand::JoinRoom(_)
+        | ServerCommand::LeaveRoom(_)
+        | ServerCommand::LockRoom(_)
+        | ServerCommand::CycleRoom(_)
+        | ServerCommand::SelectChart(_)
+        | ServerCommand::RequestStart(_)
+        | ServerCommand::Ready(_)
+        | ServerCommand::CancelReady(_)
+        | ServerCommand::Played(_)
+        | ServerCommand::Abort(_)
+        | ServerCommand::ListRooms(_)
+        | ServerCommand::SetPassword(_)
+        | ServerCommand::KickUser(_)
+        | ServerCommand::BanUser(_)
+        | ServerCommand::TransferHost(_)
+        | ServerCommand::SetRankingMetric(_)
+        | ServerCommand::SetReadyTimeoutAction(_)
+        | ServerCommand::Error(_)) => {
+            warn!("unexpected bare reply: {cmd:?}");
         }
     }
 }
@@ -0,0 +1,95 @@
+use crate::{ClientEvent, State, StreamSlot, connect, request};
+use anyhow::{Error, Result, bail};
+use phira_mp_common::{ClientCommand, ServerCommand};
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU8, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{net::TcpStream, time};
+use tracing::{info, warn};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    /// Wait before the second attempt, doubled after each failure.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up after this many failed attempts in a row. `None` never gives up.
+    pub max_attempts: Option<u32>,
+    /// The connection counts as lost after this many unanswered heartbeats.
+    pub max_ping_fails: u8,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None,
+            max_ping_fails: 3,
+        }
+    }
+}
+
+pub(crate) async fn supervise(
+    state: Arc<State>,
+    slot: StreamSlot,
+    addr: SocketAddr,
+    ping_fail_count: Arc<AtomicU8>,
+    config: ReconnectConfig,
+) {
+    loop {
+        time::sleep(CHECK_INTERVAL).await;
+        let closed = slot.read().unwrap().is_closed();
+        if !closed && ping_fail_count.load(Ordering::SeqCst) < config.max_ping_fails {
+            continue;
+        }
+        warn!("connection lost, reconnecting to {addr}");
+        state.on_disconnected();
+
+        let mut backoff = config.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            state.emit(ClientEvent::Reconnecting { attempt });
+            match reconnect(&state, &slot, addr).await {
+                Ok(()) => {
+                    info!("reconnected after {attempt} attempt(s)");
+                    ping_fail_count.store(0, Ordering::SeqCst);
+                    state.emit(ClientEvent::Reconnected);
+                    break;
+                }
+                Err(err) => warn!("failed to reconnect: {err:?}"),
+            }
+            if config.max_attempts.is_some_and(|it| attempt >= it) {
+                warn!("giving up reconnecting");
+                state.emit(ClientEvent::ReconnectFailed);
+                return;
+            }
+            time::sleep(backoff).await;
+            backoff = (backoff * 2).min(config.max_backoff);
+        }
+    }
+}
+
+async fn reconnect(state: &Arc<State>, slot: &StreamSlot, addr: SocketAddr) -> Result<()> {
+    let stream = Arc::new(connect(state, TcpStream::connect(addr).await?).await?);
+    *slot.write().unwrap() = Arc::clone(&stream);
+    let token = state.token.lock().await.clone();
+    if let Some(token) = token {
+        let command = ClientCommand::Authenticate {
+            token: token.try_into()?,
+        };
+        let (me, room) = match request(state, &stream, command).await? {
+            ServerCommand::Authenticate(res) => res.map_err(Error::msg)?,
+            other => bail!("unexpected response: {other:?}"),
+        };
+        state.on_authenticated(me, room).await;
+    }
+    Ok(())
+}
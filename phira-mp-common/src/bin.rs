@@ -1,6 +1,9 @@
+use crate::{
+    DecodeError, DecodeErrorKind, DecodeLimits, PROTOCOL_VERSION, PathSegment, render_path,
+};
 use std::{collections::HashMap, hash::Hash};
 
-use anyhow::{Result, anyhow};
+use anyhow::{Error, Result};
 use byteorder::{ByteOrder, LittleEndian as LE};
 use chrono::{DateTime, TimeZone, Utc};
 use tap::TapFallible;
@@ -11,30 +14,239 @@ pub trait BinaryData: Sized {
     fn write_binary(&self, w: &mut BinaryWriter<'_>) -> Result<()>;
 }
 
-pub struct BinaryReader<'a>(&'a [u8], usize);
+static UNLIMITED: DecodeLimits = DecodeLimits::UNLIMITED;
+
+/// Reads values laid out for some protocol version, see `#[binary(since)]`.
+pub struct BinaryReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    version: u8,
+    path: Vec<PathSegment>,
+    strict: bool,
+
+    limits: &'a DecodeLimits,
+    depth: usize,
+    total_elements: usize,
+}
 
 impl<'a> BinaryReader<'a> {
     pub fn new(data: &'a [u8]) -> Self {
-        Self(data, 0)
+        Self::with_version(data, PROTOCOL_VERSION)
+    }
+
+    pub fn with_version(data: &'a [u8], version: u8) -> Self {
+        Self {
+            data,
+            pos: 0,
+            version,
+            path: Vec::new(),
+            strict: false,
+
+            limits: &UNLIMITED,
+            depth: 0,
+            total_elements: 0,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: &'a DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    #[inline]
+    pub fn limits(&self) -> &DecodeLimits {
+        self.limits
+    }
+
+    /// Reject anything our own writer wouldn't produce: trailing bytes,
+    /// invalid UTF-8, booleans other than 0 and 1 and overlong ulebs. By
+    /// default these are let through for the sake of older peers.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    #[inline]
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    #[inline]
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Bytes read so far.
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.pos
+    }
+
+    #[inline]
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    /// Read a whole value, turning any failure into a [`DecodeError`] that
+    /// tells where it happened.
+    pub fn decode<T: BinaryData>(mut self) -> Result<T, DecodeError> {
+        let res = self.read();
+        let res = res.map_err(|err| match err.downcast::<DecodeError>() {
+            Ok(err) => err,
+            Err(err) => self.decode_error(self.pos, DecodeErrorKind::Custom(format!("{err:#}"))),
+        })?;
+        if self.strict && self.remaining() != 0 {
+            return Err(
+                self.decode_error(self.pos, DecodeErrorKind::TrailingBytes(self.remaining()))
+            );
+        }
+        Ok(res)
+    }
+
+    fn decode_error(&self, offset: usize, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError {
+            kind,
+            offset,
+            path: render_path(&self.path),
+        }
+    }
+
+    /// A [`DecodeError`] at the current position.
+    pub fn error(&self, kind: DecodeErrorKind) -> Error {
+        self.error_at(self.pos, kind)
+    }
+
+    pub fn error_at(&self, offset: usize, kind: DecodeErrorKind) -> Error {
+        self.decode_error(offset, kind).into()
+    }
+
+    /// `tag`, of type `T`, was just read and is no valid variant.
+    pub fn invalid_tag<T: Into<u64>>(&self, tag: T) -> Error {
+        self.error_at(
+            self.pos - std::mem::size_of::<T>(),
+            DecodeErrorKind::InvalidTag(tag.into()),
+        )
+    }
+
+    fn scoped<T>(
+        &mut self,
+        segment: PathSegment,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        self.path.push(segment);
+        // left in place on failure, for the error to report
+        let res = f(self)?;
+        self.path.pop();
+        Ok(res)
     }
 
-    pub fn array<T: BinaryData>(&mut self) -> Result<Vec<T>> {
-        (0..self.uleb()?).map(|_| self.read()).collect()
+    /// Read a value of the type called `name`. Used by the derive for
+    /// [`DecodeError::path`], as are [`Self::variant`] and [`Self::field`].
+    pub fn ty<T>(
+        &mut self,
+        name: &'static str,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        self.nested(|r| r.scoped(PathSegment::Type(name), f))
+    }
+
+    /// Run `f` one level deeper, see [`DecodeLimits::max_depth`].
+    pub fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth >= self.limits.max_depth {
+            return Err(self.error(DecodeErrorKind::TooDeep {
+                max: self.limits.max_depth,
+            }));
+        }
+        self.depth += 1;
+        let res = f(self)?;
+        self.depth -= 1;
+        Ok(res)
+    }
+
+    /// Read the length of a collection of `T`, checking it against the
+    /// limits.
+    pub fn collection_len<T: 'static>(&mut self) -> Result<usize> {
+        let offset = self.pos;
+        let len = usize::try_from(self.uleb()?)
+            .map_err(|_| self.error_at(offset, DecodeErrorKind::Overflow))?;
+        let max = self.limits.max_elements_of::<T>();
+        if len > max {
+            return Err(self.error_at(offset, DecodeErrorKind::TooLong { len, max }));
+        }
+        self.total_elements = self.total_elements.saturating_add(len);
+        let max = self.limits.max_total_elements;
+        if self.total_elements > max {
+            return Err(self.error_at(
+                offset,
+                DecodeErrorKind::TooLong {
+                    len: self.total_elements,
+                    max,
+                },
+            ));
+        }
+        Ok(len)
+    }
+
+    pub fn variant<T>(
+        &mut self,
+        name: &'static str,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        self.scoped(PathSegment::Variant(name), f)
+    }
+
+    pub fn field<T>(
+        &mut self,
+        name: &'static str,
+        f: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        self.scoped(PathSegment::Field(name), f)
+    }
+
+    pub fn array<T: BinaryData + 'static>(&mut self) -> Result<Vec<T>> {
+        self.nested(|r| {
+            let len = r.collection_len::<T>()?;
+            // every element takes at least a byte, usually
+            let mut res = Vec::with_capacity(len.min(r.remaining()));
+            for i in 0..len {
+                res.push(r.scoped(PathSegment::Index(i), Self::read)?);
+            }
+            Ok(res)
+        })
     }
 
     pub fn byte(&mut self) -> Result<u8> {
-        self.0
-            .get(self.1)
-            .ok_or_else(|| anyhow!("unexpected EOF"))
-            .tap_ok(|_| self.1 += 1)
+        self.data
+            .get(self.pos)
+            .ok_or_else(|| self.error(DecodeErrorKind::Eof))
+            .tap_ok(|_| self.pos += 1)
             .copied()
     }
 
     pub fn take(&mut self, n: usize) -> Result<&'a [u8]> {
-        self.0
-            .get(self.1..(self.1 + n))
-            .ok_or_else(|| anyhow!("unexpected EOF"))
-            .tap_ok(|_| self.1 += n)
+        let end = self
+            .pos
+            .checked_add(n)
+            .ok_or_else(|| self.error(DecodeErrorKind::Overflow))?;
+        self.data
+            .get(self.pos..end)
+            .ok_or_else(|| self.error(DecodeErrorKind::Eof))
+            .tap_ok(|_| self.pos = end)
+    }
+
+    /// Take `len` bytes of text. Invalid UTF-8 is replaced unless strict.
+    pub fn utf8(&mut self, len: usize) -> Result<String> {
+        let offset = self.pos;
+        let bytes = self.take(len)?;
+        if self.strict {
+            std::str::from_utf8(bytes)
+                .map(ToOwned::to_owned)
+                .map_err(|err| {
+                    self.error_at(offset + err.valid_up_to(), DecodeErrorKind::InvalidUtf8)
+                })
+        } else {
+            Ok(String::from_utf8_lossy(bytes).into_owned())
+        }
     }
 
     pub fn read<T: BinaryData>(&mut self) -> Result<T> {
@@ -42,11 +254,20 @@ impl<'a> BinaryReader<'a> {
     }
 
     pub fn uleb(&mut self) -> Result<u64> {
+        let offset = self.pos;
         let mut result = 0;
         let mut shift = 0;
         loop {
             let byte = self.read::<u8>()?;
-            result |= ((byte & 0x7f) as u64) << shift;
+            let bits = (byte & 0x7f) as u64;
+            if shift >= 64 || (shift == 63 && bits > 1) {
+                return Err(self.error_at(offset, DecodeErrorKind::Overflow));
+            }
+            result |= bits << shift;
+            // a zero final byte only pads the number out
+            if self.strict && byte == 0 && shift != 0 {
+                return Err(self.error_at(offset, DecodeErrorKind::NonCanonical));
+            }
             if byte & 0x80 == 0 {
                 break Ok(result);
             }
@@ -55,11 +276,21 @@ impl<'a> BinaryReader<'a> {
     }
 }
 
-pub struct BinaryWriter<'a>(&'a mut Vec<u8>);
+/// Writes values laid out for protocol version `.1`, see `#[binary(since)]`.
+pub struct BinaryWriter<'a>(&'a mut Vec<u8>, u8);
 
 impl<'a> BinaryWriter<'a> {
     pub fn new(vec: &'a mut Vec<u8>) -> Self {
-        Self(vec)
+        Self::with_version(vec, PROTOCOL_VERSION)
+    }
+
+    pub fn with_version(vec: &'a mut Vec<u8>, version: u8) -> Self {
+        Self(vec, version)
+    }
+
+    #[inline]
+    pub fn version(&self) -> u8 {
+        self.1
     }
 
     pub fn array<T: BinaryData>(&mut self, v: &[T]) -> Result<()> {
@@ -184,7 +415,14 @@ impl BinaryData for i64 {
 
 impl BinaryData for bool {
     fn read_binary(r: &mut BinaryReader<'_>) -> Result<Self> {
-        Ok(r.byte()? == 1)
+        match r.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte if r.is_strict() => {
+                Err(r.error_at(r.offset() - 1, DecodeErrorKind::InvalidBool(byte)))
+            }
+            _ => Ok(false),
+        }
     }
 
     fn write_binary(&self, w: &mut BinaryWriter<'_>) -> Result<()> {
@@ -205,8 +443,13 @@ impl BinaryData for f32 {
 
 impl BinaryData for String {
     fn read_binary(r: &mut BinaryReader<'_>) -> Result<Self> {
+        let offset = r.offset();
         let len = r.uleb()? as usize;
-        Ok(String::from_utf8_lossy(r.take(len)?).into_owned())
+        let max = r.limits().max_string_bytes;
+        if len > max {
+            return Err(r.error_at(offset, DecodeErrorKind::TooLong { len, max }));
+        }
+        r.utf8(len)
     }
 
     fn write_binary(&self, w: &mut BinaryWriter<'_>) -> Result<()> {
@@ -251,6 +494,16 @@ impl<T: BinaryData> BinaryData for Option<T> {
     }
 }
 
+impl<T: BinaryData> BinaryData for Box<T> {
+    fn read_binary(r: &mut BinaryReader<'_>) -> Result<Self> {
+        r.read().map(Box::new)
+    }
+
+    fn write_binary(&self, w: &mut BinaryWriter<'_>) -> Result<()> {
+        w.write(self.as_ref())
+    }
+}
+
 impl<A: BinaryData, B: BinaryData> BinaryData for Result<A, B> {
     fn read_binary(r: &mut BinaryReader<'_>) -> Result<Self> {
         Ok(if r.read::<bool>()? {
@@ -275,7 +528,7 @@ impl<A: BinaryData, B: BinaryData> BinaryData for Result<A, B> {
     }
 }
 
-impl<T: BinaryData> BinaryData for Vec<T> {
+impl<T: BinaryData + 'static> BinaryData for Vec<T> {
     fn read_binary(r: &mut BinaryReader<'_>) -> Result<Self> {
         r.array()
     }
@@ -285,9 +538,17 @@ impl<T: BinaryData> BinaryData for Vec<T> {
     }
 }
 
-impl<K: BinaryData + Eq + Hash, V: BinaryData> BinaryData for HashMap<K, V> {
+impl<K: BinaryData + Eq + Hash + 'static, V: BinaryData + 'static> BinaryData for HashMap<K, V> {
     fn read_binary(r: &mut BinaryReader<'_>) -> Result<Self> {
-        (0..r.uleb()?).map(|_| r.read::<(K, V)>()).collect()
+        r.nested(|r| {
+            let len = r.collection_len::<(K, V)>()?;
+            let mut res = HashMap::with_capacity(len.min(r.remaining()));
+            for i in 0..len {
+                let (k, v) = r.scoped(PathSegment::Index(i), BinaryReader::read::<(K, V)>)?;
+                res.insert(k, v);
+            }
+            Ok(res)
+        })
     }
 
     fn write_binary(&self, w: &mut BinaryWriter<'_>) -> Result<()> {
@@ -319,10 +580,152 @@ impl BinaryData for DateTime<Utc> {
     fn read_binary(r: &mut BinaryReader<'_>) -> Result<Self> {
         Utc.timestamp_millis_opt(r.read::<i64>()?)
             .single()
-            .ok_or_else(|| anyhow!("invalid timestamp"))
+            .ok_or_else(|| r.error(DecodeErrorKind::Custom("invalid timestamp".to_owned())))
     }
 
     fn write_binary(&self, w: &mut BinaryWriter<'_>) -> Result<()> {
         w.write_val(self.timestamp_millis())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ClientCommand, CompactPos, TouchFrame, encode_packet};
+    use std::sync::Arc;
+
+    fn encode(value: &impl BinaryData) -> Vec<u8> {
+        let mut data = Vec::new();
+        encode_packet(value, &mut data);
+        data
+    }
+
+    fn error<T: BinaryData>(r: BinaryReader<'_>) -> DecodeError {
+        r.decode::<T>().err().expect("decoded")
+    }
+
+    fn uleb(mut r: BinaryReader<'_>) -> Result<u64, DecodeError> {
+        r.uleb().map_err(|err| err.downcast().unwrap())
+    }
+
+    #[test]
+    fn uleb_overflow() {
+        let mut data = vec![0xff; 9];
+        data.push(0x01);
+        assert_eq!(uleb(BinaryReader::new(&data)), Ok(u64::MAX));
+
+        // bit 64 and up, at shift 63
+        *data.last_mut().unwrap() = 0x02;
+        let err = uleb(BinaryReader::new(&data)).unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::Overflow);
+        assert_eq!(err.offset, 0);
+
+        // an eleventh byte, at shift 70
+        let mut data = vec![0x80; 10];
+        data.push(0x01);
+        let err = uleb(BinaryReader::new(&data)).unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::Overflow);
+        assert_eq!(err.offset, 0);
+    }
+
+    #[test]
+    fn path() {
+        let frame = |points| TouchFrame {
+            time: 0.,
+            points: vec![(0, CompactPos::new(0., 0.)); points],
+        };
+        let mut frames = vec![frame(1); 3];
+        frames.push(frame(2));
+        let mut data = encode(&ClientCommand::Touches {
+            frames: Arc::new(frames),
+        });
+        data.pop();
+
+        let err = error::<ClientCommand>(BinaryReader::new(&data));
+        assert_eq!(err.kind, DecodeErrorKind::Eof);
+        assert_eq!(err.offset, data.len() - 1);
+        assert_eq!(err.path, "ClientCommand::Touches.frames[3].points[1]");
+    }
+
+    #[test]
+    fn too_long() {
+        let mut limits = DecodeLimits::default();
+        limits.max_elements = 2;
+        limits.max_total_elements = 4;
+        limits.max_string_bytes = 2;
+        let data = encode(&vec![1u8, 2, 3]);
+        let err = error::<Vec<u8>>(BinaryReader::new(&data).with_limits(&limits));
+        assert_eq!(err.kind, DecodeErrorKind::TooLong { len: 3, max: 2 });
+
+        let limits = limits.with_max_elements::<u8>(3);
+        let res = BinaryReader::new(&data).with_limits(&limits).decode();
+        assert_eq!(res, Ok(vec![1u8, 2, 3]));
+        let data = encode(&vec![1i8, 2, 3]);
+        let err = error::<Vec<i8>>(BinaryReader::new(&data).with_limits(&limits));
+        assert_eq!(err.kind, DecodeErrorKind::TooLong { len: 3, max: 2 });
+
+        // 2 + 2 + 1 elements in all
+        let data = encode(&vec![vec![1u8, 2], vec![3]]);
+        let err = error::<Vec<Vec<u8>>>(BinaryReader::new(&data).with_limits(&limits));
+        assert_eq!(err.kind, DecodeErrorKind::TooLong { len: 5, max: 4 });
+        assert_eq!(err.offset, 4);
+
+        let data = encode(&"abc".to_owned());
+        let err = error::<String>(BinaryReader::new(&data).with_limits(&limits));
+        assert_eq!(err.kind, DecodeErrorKind::TooLong { len: 3, max: 2 });
+    }
+
+    #[test]
+    fn too_deep() {
+        let mut limits = DecodeLimits::default();
+        limits.max_depth = 4;
+        let chain = |requests| {
+            (0..requests).fold(ClientCommand::Ping, |command, id| ClientCommand::Request {
+                id,
+                command: Box::new(command),
+            })
+        };
+
+        let data = encode(&chain(3));
+        let res = BinaryReader::new(&data).with_limits(&limits).decode();
+        assert!(matches!(res, Ok(ClientCommand::Request { id: 2, .. })));
+
+        let data = encode(&chain(4));
+        let err = error::<ClientCommand>(BinaryReader::new(&data).with_limits(&limits));
+        assert_eq!(err.kind, DecodeErrorKind::TooDeep { max: 4 });
+    }
+
+    #[test]
+    fn strict() {
+        let mut data = encode(&ClientCommand::Ping);
+        data.push(0);
+        let err = error::<ClientCommand>(BinaryReader::new(&data).strict());
+        assert_eq!(err.kind, DecodeErrorKind::TrailingBytes(1));
+        assert_eq!(err.offset, 1);
+        let res = BinaryReader::new(&data).decode();
+        assert!(matches!(res, Ok(ClientCommand::Ping)));
+
+        let mut data = encode(&ClientCommand::LockRoom { lock: true });
+        *data.last_mut().unwrap() = 2;
+        let err = error::<ClientCommand>(BinaryReader::new(&data).strict());
+        assert_eq!(err.kind, DecodeErrorKind::InvalidBool(2));
+        assert_eq!(err.offset, 1);
+        assert_eq!(err.path, "ClientCommand::LockRoom.lock");
+        let res = BinaryReader::new(&data).decode();
+        assert!(matches!(res, Ok(ClientCommand::LockRoom { lock: false })));
+
+        // 1, padded out to two bytes
+        let data = [0x81, 0x00];
+        let err = uleb(BinaryReader::new(&data).strict()).unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::NonCanonical);
+        assert_eq!(err.offset, 0);
+        assert_eq!(uleb(BinaryReader::new(&data)), Ok(1));
+
+        let data = [4, b'a', b'b', 0xff, b'c'];
+        let err = error::<String>(BinaryReader::new(&data).strict());
+        assert_eq!(err.kind, DecodeErrorKind::InvalidUtf8);
+        assert_eq!(err.offset, 3);
+        let res = BinaryReader::new(&data).decode::<String>();
+        assert_eq!(res.as_deref(), Ok("ab\u{fffd}c"));
+    }
+}
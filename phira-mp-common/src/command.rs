@@ -1,7 +1,6 @@
-use crate::{BinaryData, BinaryReader, BinaryWriter};
+use crate::{BinaryData, BinaryReader, BinaryWriter, DecodeErrorKind};
 use anyhow::{Result, bail};
 use half::f16;
-use phira_mp_macros::BinaryData;
 use std::{collections::HashMap, fmt::Display, sync::Arc};
 
 type SResult<T> = Result<T, String>;
@@ -63,11 +62,12 @@ impl<const N: usize> TryFrom<String> for Varchar<N> {
 }
 impl<const N: usize> BinaryData for Varchar<N> {
     fn read_binary(r: &mut BinaryReader<'_>) -> Result<Self> {
+        let offset = r.offset();
         let len = r.uleb()? as usize;
         if len > N {
-            bail!("string too long");
+            return Err(r.error_at(offset, DecodeErrorKind::TooLong { len, max: N }));
         }
-        Ok(Varchar(String::from_utf8_lossy(r.take(len)?).into_owned()))
+        Ok(Varchar(r.utf8(len)?))
     }
 
     fn write_binary(&self, w: &mut BinaryWriter<'_>) -> Result<()> {
@@ -123,6 +123,10 @@ impl BinaryData for RoomId {
 }
 
 impl<const N: usize> Varchar<N> {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
     pub fn into_inner(self) -> String {
         self.0
     }
@@ -153,7 +157,22 @@ pub struct JudgeEvent {
     pub judgement: Judgement,
 }
 
+#[derive(Debug, Clone, BinaryData)]
+pub struct PlayRecord {
+    pub score: i32,
+    pub perfect: i32,
+    pub good: i32,
+    pub bad: i32,
+    pub miss: i32,
+    pub max_combo: i32,
+    pub accuracy: f32,
+    pub full_combo: bool,
+    pub std: f32,
+    pub std_score: f32,
+}
+
 #[derive(Debug, BinaryData)]
+#[rustfmt::skip]
 pub enum ClientCommand {
     Ping,
 
@@ -163,8 +182,8 @@ pub enum ClientCommand {
     Touches { frames: Arc<Vec<TouchFrame>> },
     Judges { judges: Arc<Vec<JudgeEvent>> },
 
-    CreateRoom { id: RoomId },
-    JoinRoom { id: RoomId, monitor: bool },
+    CreateRoom { id: RoomId, password: Option<Varchar<32>>, private: bool },
+    JoinRoom { id: RoomId, monitor: bool, password: Option<Varchar<32>> },
     LeaveRoom,
     LockRoom { lock: bool },
     CycleRoom { cycle: bool },
@@ -175,6 +194,53 @@ pub enum ClientCommand {
     CancelReady,
     Played { id: i32 },
     Abort,
+
+    SubmitRecord { record: PlayRecord },
+    ListRooms { page: u32 },
+    SetPassword { password: Option<Varchar<32>> },
+    KickUser { user: i32 },
+    BanUser { user: i32 },
+    TransferHost { user: i32 },
+    SetRankingMetric { metric: RankingMetric },
+    SetReadyTimeoutAction { action: ReadyTimeoutAction },
+    /// `command`, answered with a [`ServerCommand::Response`] carrying the
+    /// same `id` instead of a bare reply.
+    Request { id: u32, command: Box<ClientCommand> },
+}
+
+#[derive(Debug, Default, BinaryData, Clone, Copy, PartialEq, Eq)]
+pub enum RankingMetric {
+    #[default]
+    Score,
+    Accuracy,
+    MaxCombo,
+    /// Timing standard deviation, lower is better.
+    Std,
+}
+
+/// What happens when not everyone is ready before the ready timeout.
+#[derive(Debug, Default, BinaryData, Clone, Copy, PartialEq, Eq)]
+pub enum ReadyTimeoutAction {
+    /// Go back to chart selection.
+    #[default]
+    Cancel,
+    /// Start anyway; unready players sit the game out.
+    StartWithout,
+}
+
+#[derive(Clone, Debug, BinaryData)]
+pub struct PlayerResult {
+    pub user: i32,
+    pub rank: u32,
+    pub score: i32,
+    pub accuracy: f32,
+    pub full_combo: bool,
+    pub max_combo: i32,
+    pub perfect: i32,
+    pub good: i32,
+    pub bad: i32,
+    pub miss: i32,
+    pub std: f32,
 }
 
 #[derive(Clone, Debug, BinaryData)]
@@ -231,6 +297,39 @@ pub enum Message {
     CycleRoom {
         cycle: bool,
     },
+    /// Sent instead of [`Message::LeaveRoom`] when the host removes `user`.
+    KickUser {
+        user: i32,
+        name: String,
+    },
+    BanUser {
+        user: i32,
+        name: String,
+    },
+    GameResults {
+        metric: RankingMetric,
+        ranking: Vec<PlayerResult>,
+        aborted: Vec<i32>,
+    },
+    RankingMetric {
+        metric: RankingMetric,
+    },
+    /// Everyone has `seconds` left to get ready.
+    ReadyCountdown {
+        seconds: f32,
+    },
+    ReadyTimeoutAction {
+        action: ReadyTimeoutAction,
+    },
+    /// `user` wasn't ready in time and doesn't take part in this game.
+    SitOut {
+        user: i32,
+    },
+    /// Sent right before [`Message::StartPlaying`]: everyone should start
+    /// at `time` on the server clock, in microseconds.
+    StartAt {
+        time: u64,
+    },
 }
 
 #[derive(Debug, BinaryData, Clone, Copy)]
@@ -263,6 +362,8 @@ pub struct ClientRoomState {
     pub is_host: bool,
     pub is_ready: bool,
     pub users: HashMap<i32, UserInfo>,
+    /// Seconds left to get ready, while waiting for ready.
+    pub ready_countdown: Option<f32>,
 }
 
 #[derive(Debug, BinaryData, Clone)]
@@ -272,9 +373,34 @@ pub struct JoinRoomResponse {
     pub live: bool,
 }
 
+#[derive(Debug, BinaryData, Clone)]
+pub struct RoomInfo {
+    pub id: RoomId,
+    pub host: String,
+    pub users: u32,
+    pub max_users: u32,
+    pub state: RoomState,
+    pub locked: bool,
+    pub cycle: bool,
+    pub live: bool,
+    pub password: bool,
+    pub chart: Option<String>,
+}
+
+#[derive(Debug, BinaryData, Clone)]
+pub struct RoomList {
+    pub rooms: Vec<RoomInfo>,
+    pub page: u32,
+    pub total: u32,
+}
+
 #[derive(Clone, Debug, BinaryData)]
 pub enum ServerCommand {
-    Pong,
+    /// `time` is the server clock, in microseconds, or 0 without
+    /// [`Capabilities::CLOCK_SYNC`](crate::Capabilities::CLOCK_SYNC).
+    Pong {
+        time: u64,
+    },
 
     Authenticate(SResult<(UserInfo, Option<ClientRoomState>)>),
     Chat(SResult<()>),
@@ -305,4 +431,19 @@ pub enum ServerCommand {
     CancelReady(SResult<()>),
     Played(SResult<()>),
     Abort(SResult<()>),
+
+    ListRooms(SResult<RoomList>),
+    SetPassword(SResult<()>),
+    KickUser(SResult<()>),
+    BanUser(SResult<()>),
+    TransferHost(SResult<()>),
+    SetRankingMetric(SResult<()>),
+    SetReadyTimeoutAction(SResult<()>),
+    Response {
+        id: u32,
+        response: Box<ServerCommand>,
+    },
+    /// The reply to a command that was refused outright, such as a
+    /// [`ClientCommand::Request`] nested in another.
+    Error(String),
 }
@@ -0,0 +1,101 @@
+use std::fmt;
+
+/// What went wrong while decoding, see [`DecodeError`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeErrorKind {
+    /// The data ended in the middle of a value.
+    Eof,
+    /// An enum tag no variant has.
+    InvalidTag(u64),
+    /// A length above what the value allows.
+    TooLong {
+        len: usize,
+        max: usize,
+    },
+    InvalidUtf8,
+    InvalidBool(u8),
+    /// A number encoded with more bytes than it needs.
+    NonCanonical,
+    /// Data left over after the value.
+    TrailingBytes(usize),
+    /// A number that doesn't fit where it goes.
+    Overflow,
+    /// Values nested deeper than allowed.
+    TooDeep {
+        max: usize,
+    },
+    Custom(String),
+}
+
+impl fmt::Display for DecodeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Eof => write!(f, "unexpected EOF"),
+            Self::InvalidTag(tag) => write!(f, "invalid tag {tag}"),
+            Self::TooLong { len, max } => write!(f, "length {len} exceeds {max}"),
+            Self::InvalidUtf8 => write!(f, "invalid UTF-8"),
+            Self::InvalidBool(byte) => write!(f, "invalid bool {byte}"),
+            Self::NonCanonical => write!(f, "non-canonical encoding"),
+            Self::TrailingBytes(len) => write!(f, "trailing data ({len} bytes)"),
+            Self::Overflow => write!(f, "integer overflow"),
+            Self::TooDeep { max } => write!(f, "nested deeper than {max}"),
+            Self::Custom(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+/// A value that couldn't be decoded, and where.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeError {
+    pub kind: DecodeErrorKind,
+    /// Byte offset into the packet.
+    pub offset: usize,
+    /// The value being decoded, e.g.
+    /// `ClientCommand::Touches.frames[3].points[1]`. Empty at the top level
+    /// of types without a derived [`BinaryData`](crate::BinaryData) impl.
+    pub path: String,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{} at byte {}", self.kind, self.offset)
+        } else {
+            write!(f, "{} at {} (byte {})", self.kind, self.path, self.offset)
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// One step of [`DecodeError::path`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum PathSegment {
+    Type(&'static str),
+    Variant(&'static str),
+    Field(&'static str),
+    Index(usize),
+}
+
+pub(crate) fn render_path(path: &[PathSegment]) -> String {
+    let mut res = String::new();
+    for (i, segment) in path.iter().enumerate() {
+        match segment {
+            // only the outermost type is named, the rest is clear from the fields
+            PathSegment::Type(name) if i == 0 => res.push_str(name),
+            PathSegment::Type(_) => {}
+            PathSegment::Variant(name) => {
+                res.push_str("::");
+                res.push_str(name);
+            }
+            PathSegment::Field(name) => {
+                res.push('.');
+                res.push_str(name);
+            }
+            PathSegment::Index(index) => {
+                res.push_str(&format!("[{index}]"));
+            }
+        }
+    }
+    res
+}
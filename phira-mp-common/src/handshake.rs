@@ -0,0 +1,214 @@
+use crate::{BinaryData, BinaryReader, BinaryWriter};
+use anyhow::{Result, bail};
+use std::{fmt, ops::BitOr};
+
+/// Wire layout spoken by this crate.
+pub const PROTOCOL_VERSION: u8 = 2;
+/// Layout of clients that predate the handshake and open the connection
+/// with this bare version byte.
+pub const LEGACY_VERSION: u8 = 1;
+/// First byte of a handshake. Legacy clients never send it.
+pub const HANDSHAKE_MARKER: u8 = 0xff;
+
+/// Optional protocol features, agreed upon per connection.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Large packets are LZ4 compressed.
+    pub const COMPRESSION: Self = Self(1 << 0);
+    /// Commands may be wrapped in `Request`s and answered with `Response`s.
+    pub const REQUEST_IDS: Self = Self(1 << 1);
+    /// `Pong` carries the server clock and games start at a scheduled time.
+    pub const CLOCK_SYNC: Self = Self(1 << 2);
+    /// Messages beyond the legacy set, e.g. game results and kicks.
+    pub const EXTENDED_MESSAGES: Self = Self(1 << 3);
+
+    const NAMES: [(Self, &'static str); 4] = [
+        (Self::COMPRESSION, "compression"),
+        (Self::REQUEST_IDS, "request-ids"),
+        (Self::CLOCK_SYNC, "clock-sync"),
+        (Self::EXTENDED_MESSAGES, "extended-messages"),
+    ];
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Everything this crate supports.
+    pub const fn all() -> Self {
+        Self(
+            Self::COMPRESSION.0
+                | Self::REQUEST_IDS.0
+                | Self::CLOCK_SYNC.0
+                | Self::EXTENDED_MESSAGES.0,
+        )
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Unknown bits are kept, so that they survive a round trip.
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set()
+            .entries(
+                Self::NAMES
+                    .iter()
+                    .filter(|(it, _)| self.contains(*it))
+                    .map(|(_, name)| name),
+            )
+            .finish()
+    }
+}
+
+impl BinaryData for Capabilities {
+    fn read_binary(r: &mut BinaryReader<'_>) -> Result<Self> {
+        r.read().map(Self)
+    }
+
+    fn write_binary(&self, w: &mut BinaryWriter<'_>) -> Result<()> {
+        w.write_val(self.0)
+    }
+}
+
+/// What one end of a connection is able to speak.
+#[derive(Debug, Clone, Copy, BinaryData)]
+pub struct Hello {
+    pub min_version: u8,
+    pub max_version: u8,
+    pub capabilities: Capabilities,
+}
+
+/// Only the current version: servers that predate the handshake can't be
+/// talked to, as they'd take the marker for a version byte.
+impl Default for Hello {
+    fn default() -> Self {
+        Self {
+            min_version: PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::all(),
+        }
+    }
+}
+
+/// What a connection ended up speaking.
+#[derive(Debug, Clone, Copy, BinaryData)]
+pub struct Protocol {
+    pub version: u8,
+    pub capabilities: Capabilities,
+}
+
+impl Protocol {
+    /// A client that opened with a bare version byte.
+    pub fn legacy(version: u8) -> Self {
+        Self {
+            version,
+            capabilities: Capabilities::empty(),
+        }
+    }
+
+    #[inline]
+    pub fn supports(&self, capabilities: Capabilities) -> bool {
+        self.capabilities.contains(capabilities)
+    }
+}
+
+/// Which side of the handshake to take in [`Stream::new`](crate::Stream::new).
+#[derive(Debug, Clone, Copy)]
+pub enum Handshake {
+    /// Offer our range to the other end and take what it picks.
+    Offer(Hello),
+    /// Pick the best protocol both ends support.
+    Accept(Hello),
+}
+
+impl Hello {
+    /// The highest version and the capabilities both `self` and `other`
+    /// support.
+    pub fn negotiate(&self, other: &Hello) -> Result<Protocol> {
+        let version = self.max_version.min(other.max_version);
+        if version < self.min_version.max(other.min_version) {
+            bail!(
+                "no common protocol version ({}..={} and {}..={})",
+                self.min_version,
+                self.max_version,
+                other.min_version,
+                other.max_version
+            );
+        }
+        Ok(Protocol {
+            version,
+            capabilities: self.capabilities.intersection(other.capabilities),
+        })
+    }
+
+    pub fn accepts_legacy(&self, version: u8) -> bool {
+        (self.min_version..=self.max_version).contains(&version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(min_version: u8, max_version: u8, capabilities: Capabilities) -> Hello {
+        Hello {
+            min_version,
+            max_version,
+            capabilities,
+        }
+    }
+
+    #[test]
+    fn negotiate_overlap() {
+        let server = hello(1, 3, Capabilities::COMPRESSION | Capabilities::CLOCK_SYNC);
+        let client = hello(
+            2,
+            4,
+            Capabilities::CLOCK_SYNC | Capabilities::from_bits(1 << 31),
+        );
+        for protocol in [server.negotiate(&client), client.negotiate(&server)] {
+            let protocol = protocol.unwrap();
+            assert_eq!(protocol.version, 3);
+            assert_eq!(protocol.capabilities, Capabilities::CLOCK_SYNC);
+        }
+
+        let protocol = server
+            .negotiate(&hello(1, 1, Capabilities::empty()))
+            .unwrap();
+        assert_eq!(protocol.version, 1);
+        assert_eq!(protocol.capabilities, Capabilities::empty());
+    }
+
+    #[test]
+    fn negotiate_refusal() {
+        let server = hello(2, 3, Capabilities::all());
+        assert!(server.negotiate(&hello(1, 1, Capabilities::all())).is_err());
+        assert!(server.negotiate(&hello(4, 5, Capabilities::all())).is_err());
+        assert!(!server.accepts_legacy(1));
+        assert!(hello(1, 2, Capabilities::all()).accepts_legacy(1));
+    }
+}
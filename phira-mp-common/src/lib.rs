@@ -1,14 +1,38 @@
+extern crate self as phira_mp_common;
+
 mod bin;
 pub use bin::*;
 
 mod command;
 pub use command::*;
 
+mod error;
+pub use error::*;
+
+mod limits;
+pub use limits::*;
+
+mod handshake;
+pub use handshake::*;
+
+pub mod v1;
+
+pub use phira_mp_macros::BinaryData;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use anyhow::Result;
+}
+
 use anyhow::{Error, Result, bail};
-use std::{future::Future, marker::PhantomData, sync::Arc, time::Duration};
+use byteorder::{ByteOrder, LittleEndian as LE};
+use std::{borrow::Cow, future::Future, marker::PhantomData, sync::Arc, time::Duration};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
     sync::mpsc,
     task::JoinHandle,
 };
@@ -18,19 +42,191 @@ pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(3);
 pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(2);
 pub const HEARTBEAT_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
+pub const ROOM_LIST_PAGE_SIZE: usize = 20;
+
+/// Sender id of server-generated [`Message::Chat`] lines. Never a user's id.
+pub const SYSTEM_USER: i32 = -1;
+
 pub fn encode_packet(payload: &impl BinaryData, vec: &mut Vec<u8>) {
     BinaryWriter::new(vec).write(payload).unwrap();
 }
 
-pub fn decode_packet<T>(data: &[u8]) -> Result<T>
+pub fn decode_packet<T>(data: &[u8]) -> Result<T, DecodeError>
 where
     T: BinaryData,
 {
-    BinaryReader::new(data).read()
+    BinaryReader::new(data).decode()
+}
+
+/// How packets are laid out on the wire, picked per connection once the
+/// [`Protocol`] is known. See [`Stream::with_codec`].
+pub trait Codec<S, R>: Send + Sync {
+    fn encode(&self, payload: &S, vec: &mut Vec<u8>) -> Result<()>;
+    fn decode(&self, data: &[u8]) -> Result<R>;
+}
+
+/// The layout given by [`BinaryData`] itself, at protocol version `version`.
+pub struct BinaryCodec {
+    pub version: u8,
+    /// Applied to received packets.
+    pub limits: DecodeLimits,
+    /// Decode received packets with [`BinaryReader::strict`].
+    pub strict: bool,
+}
+
+impl BinaryCodec {
+    pub fn new(version: u8) -> Self {
+        Self {
+            version,
+            limits: DecodeLimits::UNLIMITED,
+            strict: false,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+}
+
+impl<S: BinaryData, R: BinaryData> Codec<S, R> for BinaryCodec {
+    fn encode(&self, payload: &S, vec: &mut Vec<u8>) -> Result<()> {
+        BinaryWriter::with_version(vec, self.version).write(payload)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<R> {
+        let mut r = BinaryReader::with_version(data, self.version).with_limits(&self.limits);
+        if self.strict {
+            r = r.strict();
+        }
+        Ok(r.decode()?)
+    }
+}
+
+const MAX_PACKET_SIZE: usize = 2 * 1024 * 1024;
+/// Packets smaller than this aren't worth compressing.
+const COMPRESSION_THRESHOLD: usize = 256;
+
+async fn write_frame(write: &mut (impl AsyncWriteExt + Unpin), data: &[u8]) -> Result<()> {
+    let mut len_buf = [0u8; 5];
+    let mut x = data.len() as u32;
+    let mut n = 0;
+    loop {
+        len_buf[n] = (x & 0x7f) as u8;
+        n += 1;
+        x >>= 7;
+        if x == 0 {
+            break;
+        } else {
+            len_buf[n - 1] |= 0x80;
+        }
+    }
+    write.write_all(&len_buf[..n]).await?;
+    write.write_all(data).await?;
+    Ok(())
+}
+
+#[allow(clippy::read_zero_byte_vec)]
+async fn read_frame(read: &mut (impl AsyncReadExt + Unpin), buffer: &mut Vec<u8>) -> Result<()> {
+    let mut len = 0u32;
+    let mut pos = 0;
+    loop {
+        let byte = read.read_u8().await?;
+        len |= ((byte & 0x7f) as u32) << pos;
+        pos += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if pos > 32 {
+            bail!("invalid length");
+        }
+    }
+    let len = len as usize;
+    if len > MAX_PACKET_SIZE {
+        bail!("data packet too large");
+    }
+    buffer.resize(len, 0);
+    read.read_exact(buffer).await?;
+    Ok(())
+}
+
+/// With [`Capabilities::COMPRESSION`], every packet starts with a flag byte
+/// telling whether the rest is LZ4 compressed.
+fn compress(data: &[u8], out: &mut Vec<u8>) {
+    out.clear();
+    if data.len() < COMPRESSION_THRESHOLD {
+        out.push(0);
+        out.extend_from_slice(data);
+    } else {
+        out.push(1);
+        out.extend_from_slice(&lz4_flex::compress_prepend_size(data));
+    }
+}
+
+fn decompress(data: &[u8]) -> Result<Cow<'_, [u8]>> {
+    match data.split_first() {
+        Some((0, data)) => Ok(Cow::Borrowed(data)),
+        Some((1, data)) => {
+            let Some(size) = data.get(..4) else {
+                bail!("truncated compressed packet");
+            };
+            if LE::read_u32(size) as usize > MAX_PACKET_SIZE {
+                bail!("data packet too large");
+            }
+            Ok(Cow::Owned(lz4_flex::decompress_size_prepended(data)?))
+        }
+        Some((flag, _)) => bail!("invalid compression flag {flag}"),
+        None => bail!("empty packet"),
+    }
+}
+
+async fn handshake(
+    read: &mut OwnedReadHalf,
+    write: &mut OwnedWriteHalf,
+    handshake: Handshake,
+) -> Result<Protocol> {
+    let mut buffer = Vec::new();
+    match handshake {
+        Handshake::Offer(hello) => {
+            write.write_u8(HANDSHAKE_MARKER).await?;
+            encode_packet(&hello, &mut buffer);
+            write_frame(write, &buffer).await?;
+            read_frame(read, &mut buffer).await?;
+            decode_packet::<Result<Protocol, String>>(&buffer)?.map_err(Error::msg)
+        }
+        Handshake::Accept(hello) => {
+            let first = read.read_u8().await?;
+            if first != HANDSHAKE_MARKER {
+                if !hello.accepts_legacy(first) {
+                    bail!("unsupported legacy protocol version {first}");
+                }
+                return Ok(Protocol::legacy(first));
+            }
+            read_frame(read, &mut buffer).await?;
+            // the peer isn't trusted yet
+            let offer: Hello = BinaryReader::new(&buffer)
+                .with_limits(&DecodeLimits::default())
+                .strict()
+                .decode()?;
+            let res = hello.negotiate(&offer);
+            buffer.clear();
+            encode_packet(
+                &res.as_ref().map(|it| *it).map_err(ToString::to_string),
+                &mut buffer,
+            );
+            write_frame(write, &buffer).await?;
+            res
+        }
+    }
 }
 
 pub struct Stream<S, R> {
-    version: u8,
+    protocol: Protocol,
 
     send_tx: Arc<mpsc::Sender<S>>,
 
@@ -46,8 +242,28 @@ where
     R: BinaryData + std::fmt::Debug + Send + 'static,
 {
     pub async fn new<F>(
-        version: Option<u8>,
+        handshake: Handshake,
         stream: TcpStream,
+        handler: Box<dyn FnMut(Arc<mpsc::Sender<S>>, R) -> F + Send + Sync>,
+    ) -> Result<Self>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Self::with_codec(
+            handshake,
+            stream,
+            |protocol| Ok(Arc::new(BinaryCodec::new(protocol.version))),
+            handler,
+        )
+        .await
+    }
+
+    /// Like [`Stream::new`], encoding packets with whatever `codec` picks for
+    /// the negotiated protocol. `codec` may refuse the protocol.
+    pub async fn with_codec<F>(
+        handshake: Handshake,
+        stream: TcpStream,
+        codec: impl FnOnce(&Protocol) -> Result<Arc<dyn Codec<S, R>>>,
         mut handler: Box<dyn FnMut(Arc<mpsc::Sender<S>>, R) -> F + Send + Sync>,
     ) -> Result<Self>
     where
@@ -55,44 +271,32 @@ where
     {
         stream.set_nodelay(true)?;
         let (mut read, mut write) = stream.into_split();
-        let version = if let Some(version) = version {
-            write.write_u8(version).await?;
-            version
-        } else {
-            read.read_u8().await?
-        };
+        let protocol = self::handshake(&mut read, &mut write, handshake).await?;
+        let codec = codec(&protocol)?;
+        let compressed = protocol.supports(Capabilities::COMPRESSION);
 
         let (send_tx, mut send_rx) = mpsc::channel(1024);
         let send_tx = Arc::new(send_tx);
         let send_task_handle = tokio::spawn({
+            let codec = Arc::clone(&codec);
             async move {
                 let mut buffer = Vec::new();
-                let mut len_buf = [0u8; 5];
+                let mut compressed_buffer = Vec::new();
                 while let Some(payload) = send_rx.recv().await {
                     buffer.clear();
-                    encode_packet(&payload, &mut buffer);
-                    trace!("sending {} bytes ({payload:?}): {buffer:?}", buffer.len());
-
-                    let mut x = buffer.len() as u32;
-                    let mut n = 0;
-                    loop {
-                        len_buf[n] = (x & 0x7f) as u8;
-                        n += 1;
-                        x >>= 7;
-                        if x == 0 {
-                            break;
-                        } else {
-                            len_buf[n - 1] |= 0x80;
-                        }
+                    if let Err(err) = codec.encode(&payload, &mut buffer) {
+                        error!("failed to encode {payload:?}: {err:?}");
+                        continue;
                     }
+                    trace!("sending {} bytes ({payload:?}): {buffer:?}", buffer.len());
 
-                    if let Err(err) = async {
-                        write.write_all(&len_buf[..n]).await?;
-                        write.write_all(&buffer).await?;
-                        Ok::<_, Error>(())
-                    }
-                    .await
-                    {
+                    let data = if compressed {
+                        compress(&buffer, &mut compressed_buffer);
+                        &compressed_buffer
+                    } else {
+                        &buffer
+                    };
+                    if let Err(err) = write_frame(&mut write, data).await {
                         error!("failed to send: {err:?}");
                     }
                 }
@@ -101,33 +305,24 @@ where
 
         let recv_task_handle = tokio::spawn({
             let send_tx = Arc::clone(&send_tx);
-            #[allow(clippy::read_zero_byte_vec)]
             async move {
                 let mut buffer = Vec::new();
                 loop {
-                    let mut len = 0u32;
-                    let mut pos = 0;
-                    loop {
-                        let byte = read.read_u8().await?;
-                        len |= ((byte & 0x7f) as u32) << pos;
-                        pos += 7;
-                        if byte & 0x80 == 0 {
-                            break;
-                        }
-                        if pos > 32 {
-                            bail!("invalid length");
-                        }
-                    }
-                    if len > 2 * 1024 * 1024 {
-                        bail!("data packet too large");
-                    }
-                    let len = len as usize;
-
-                    buffer.resize(len, 0);
-                    read.read_exact(&mut buffer).await?;
+                    read_frame(&mut read, &mut buffer).await?;
                     trace!("received {} bytes: {buffer:?}", buffer.len());
 
-                    let payload: R = match decode_packet(&buffer) {
+                    let data = if compressed {
+                        match decompress(&buffer) {
+                            Ok(data) => data,
+                            Err(err) => {
+                                warn!("invalid packet: {err:?} {buffer:?}");
+                                break;
+                            }
+                        }
+                    } else {
+                        Cow::Borrowed(&buffer[..])
+                    };
+                    let payload = match codec.decode(&data) {
                         Ok(val) => val,
                         Err(err) => {
                             warn!("invalid packet: {err:?} {buffer:?}");
@@ -142,7 +337,7 @@ where
         });
 
         Ok(Self {
-            version,
+            protocol,
 
             send_tx,
 
@@ -153,8 +348,17 @@ where
         })
     }
 
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
     pub fn version(&self) -> u8 {
-        self.version
+        self.protocol.version
+    }
+
+    /// Whether the connection is gone, i.e. nothing more will be received.
+    pub fn is_closed(&self) -> bool {
+        self.recv_task_handle.is_finished()
     }
 
     pub async fn send(&self, payload: S) -> Result<()> {
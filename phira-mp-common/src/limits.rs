@@ -0,0 +1,60 @@
+use std::any::TypeId;
+
+/// Bounds on what a single decoded value may ask for, so that a small packet
+/// can't make the reader allocate or recurse without end. Exceeding one is a
+/// [`DecodeErrorKind::TooLong`](crate::DecodeErrorKind::TooLong) or
+/// [`DecodeErrorKind::TooDeep`](crate::DecodeErrorKind::TooDeep).
+#[derive(Debug, Clone)]
+pub struct DecodeLimits {
+    /// Elements in one collection.
+    pub max_elements: usize,
+    /// Elements in all collections of the value together.
+    pub max_total_elements: usize,
+    /// Nesting of types and collections.
+    pub max_depth: usize,
+    /// Bytes in one string.
+    pub max_string_bytes: usize,
+    /// `max_elements` for collections of particular element types.
+    overrides: Vec<(TypeId, usize)>,
+}
+
+impl DecodeLimits {
+    /// No limits, for data from a trusted peer.
+    pub const UNLIMITED: Self = Self {
+        max_elements: usize::MAX,
+        max_total_elements: usize::MAX,
+        max_depth: usize::MAX,
+        max_string_bytes: usize::MAX,
+        overrides: Vec::new(),
+    };
+
+    /// Allow up to `max` elements in collections of `T`, instead of
+    /// [`Self::max_elements`].
+    pub fn with_max_elements<T: 'static>(mut self, max: usize) -> Self {
+        let id = TypeId::of::<T>();
+        self.overrides.retain(|(it, _)| *it != id);
+        self.overrides.push((id, max));
+        self
+    }
+
+    pub fn max_elements_of<T: 'static>(&self) -> usize {
+        let id = TypeId::of::<T>();
+        self.overrides
+            .iter()
+            .find(|(it, _)| *it == id)
+            .map_or(self.max_elements, |(_, max)| *max)
+    }
+}
+
+/// Enough for anything a well-behaved peer sends.
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_elements: 256,
+            max_total_elements: 16384,
+            max_depth: 16,
+            max_string_bytes: 4096,
+            overrides: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Wire layout of protocol version 1, spoken by clients that predate the
+//! handshake, and conversions from and to the current commands.
+
+use crate::{
+    BinaryData, JoinRoomResponse, JudgeEvent, RoomId, RoomState, TouchFrame, UserInfo, Varchar,
+};
+use anyhow::{Result, bail};
+use std::{collections::HashMap, sync::Arc};
+
+type SResult<T> = Result<T, String>;
+
+#[derive(Debug, BinaryData)]
+pub enum ClientCommand {
+    Ping,
+
+    Authenticate { token: Varchar<32> },
+    Chat { message: Varchar<200> },
+
+    Touches { frames: Arc<Vec<TouchFrame>> },
+    Judges { judges: Arc<Vec<JudgeEvent>> },
+
+    CreateRoom { id: RoomId },
+    JoinRoom { id: RoomId, monitor: bool },
+    LeaveRoom,
+    LockRoom { lock: bool },
+    CycleRoom { cycle: bool },
+
+    SelectChart { id: i32 },
+    RequestStart,
+    Ready,
+    CancelReady,
+    Played { id: i32 },
+    Abort,
+}
+
+#[derive(Clone, Debug, BinaryData)]
+pub enum Message {
+    Chat {
+        user: i32,
+        content: String,
+    },
+    CreateRoom {
+        user: i32,
+    },
+    JoinRoom {
+        user: i32,
+        name: String,
+    },
+    LeaveRoom {
+        user: i32,
+        name: String,
+    },
+    NewHost {
+        user: i32,
+    },
+    SelectChart {
+        user: i32,
+        name: String,
+        id: i32,
+    },
+    GameStart {
+        user: i32,
+    },
+    Ready {
+        user: i32,
+    },
+    CancelReady {
+        user: i32,
+    },
+    CancelGame {
+        user: i32,
+    },
+    StartPlaying,
+    Played {
+        user: i32,
+        score: i32,
+        accuracy: f32,
+        full_combo: bool,
+    },
+    GameEnd,
+    Abort {
+        user: i32,
+    },
+    LockRoom {
+        lock: bool,
+    },
+    CycleRoom {
+        cycle: bool,
+    },
+}
+
+#[derive(Debug, BinaryData, Clone)]
+pub struct ClientRoomState {
+    pub id: RoomId,
+    pub state: RoomState,
+    pub live: bool,
+    pub locked: bool,
+    pub cycle: bool,
+    pub is_host: bool,
+    pub is_ready: bool,
+    pub users: HashMap<i32, UserInfo>,
+}
+
+#[derive(Clone, Debug, BinaryData)]
+pub enum ServerCommand {
+    Pong,
+
+    Authenticate(SResult<(UserInfo, Option<ClientRoomState>)>),
+    Chat(SResult<()>),
+
+    Touches {
+        player: i32,
+        frames: Arc<Vec<TouchFrame>>,
+    },
+    Judges {
+        player: i32,
+        judges: Arc<Vec<JudgeEvent>>,
+    },
+
+    Message(Message),
+    ChangeState(RoomState),
+    ChangeHost(bool),
+
+    CreateRoom(SResult<()>),
+    JoinRoom(SResult<JoinRoomResponse>),
+    OnJoinRoom(UserInfo),
+    LeaveRoom(SResult<()>),
+    LockRoom(SResult<()>),
+    CycleRoom(SResult<()>),
+
+    SelectChart(SResult<()>),
+    RequestStart(SResult<()>),
+    Ready(SResult<()>),
+    CancelReady(SResult<()>),
+    Played(SResult<()>),
+    Abort(SResult<()>),
+}
+
+impl From<ClientCommand> for crate::ClientCommand {
+    fn from(cmd: ClientCommand) -> Self {
+        use ClientCommand as C;
+        match cmd {
+            C::Ping => Self::Ping,
+            C::Authenticate { token } => Self::Authenticate { token },
+            C::Chat { message } => Self::Chat { message },
+            C::Touches { frames } => Self::Touches { frames },
+            C::Judges { judges } => Self::Judges { judges },
+            C::CreateRoom { id } => Self::CreateRoom {
+                id,
+                password: None,
+                private: false,
+            },
+            C::JoinRoom { id, monitor } => Self::JoinRoom {
+                id,
+                monitor,
+                password: None,
+            },
+            C::LeaveRoom => Self::LeaveRoom,
+            C::LockRoom { lock } => Self::LockRoom { lock },
+            C::CycleRoom { cycle } => Self::CycleRoom { cycle },
+            C::SelectChart { id } => Self::SelectChart { id },
+            C::RequestStart => Self::RequestStart,
+            C::Ready => Self::Ready,
+            C::CancelReady => Self::CancelReady,
+            C::Played { id } => Self::Played { id },
+            C::Abort => Self::Abort,
+        }
+    }
+}
+
+impl From<crate::ClientRoomState> for ClientRoomState {
+    fn from(state: crate::ClientRoomState) -> Self {
+        Self {
+            id: state.id,
+            state: state.state,
+            live: state.live,
+            locked: state.locked,
+            cycle: state.cycle,
+            is_host: state.is_host,
+            is_ready: state.is_ready,
+            users: state.users,
+        }
+    }
+}
+
+impl TryFrom<crate::Message> for Message {
+    type Error = anyhow::Error;
+
+    fn try_from(msg: crate::Message) -> Result<Self> {
+        use crate::Message as M;
+        Ok(match msg {
+            M::Chat { user, content } => Self::Chat { user, content },
+            M::CreateRoom { user } => Self::CreateRoom { user },
+            M::JoinRoom { user, name } => Self::JoinRoom { user, name },
+            M::LeaveRoom { user, name } => Self::LeaveRoom { user, name },
+            M::NewHost { user } => Self::NewHost { user },
+            M::SelectChart { user, name, id } => Self::SelectChart { user, name, id },
+            M::GameStart { user } => Self::GameStart { user },
+            M::Ready { user } => Self::Ready { user },
+            M::CancelReady { user } => Self::CancelReady { user },
+            M::CancelGame { user } => Self::CancelGame { user },
+            M::StartPlaying => Self::StartPlaying,
+            M::Played {
+                user,
+                score,
+                accuracy,
+                full_combo,
+            } => Self::Played {
+                user,
+                score,
+                accuracy,
+                full_combo,
+            },
+            M::GameEnd => Self::GameEnd,
+            M::Abort { user } => Self::Abort { user },
+            M::LockRoom { lock } => Self::LockRoom { lock },
+            M::CycleRoom { cycle } => Self::CycleRoom { cycle },
+            msg => bail!("{msg:?} has no v1 equivalent"),
+        })
+    }
+}
+
+impl TryFrom<crate::ServerCommand> for ServerCommand {
+    type Error = anyhow::Error;
+
+    fn try_from(cmd: crate::ServerCommand) -> Result<Self> {
+        use crate::ServerCommand as S;
+        Ok(match cmd {
+            S::Pong { .. } => Self::Pong,
+            S::Authenticate(res) => {
+                Self::Authenticate(res.map(|(me, room)| (me, room.map(Into::into))))
+            }
+            S::Chat(res) => Self::Chat(res),
+            S::Touches { player, frames } => Self::Touches { player, frames },
+            S::Judges { player, judges } => Self::Judges { player, judges },
+            S::Message(msg) => Self::Message(msg.try_into()?),
+            S::ChangeState(state) => Self::ChangeState(state),
+            S::ChangeHost(is_host) => Self::ChangeHost(is_host),
+            S::CreateRoom(res) => Self::CreateRoom(res),
+            S::JoinRoom(res) => Self::JoinRoom(res),
+            S::OnJoinRoom(user) => Self::OnJoinRoom(user),
+            S::LeaveRoom(res) => Self::LeaveRoom(res),
+            S::LockRoom(res) => Self::LockRoom(res),
+            S::CycleRoom(res) => Self::CycleRoom(res),
+            S::SelectChart(res) => Self::SelectChart(res),
+            S::RequestStart(res) => Self::RequestStart(res),
+            S::Ready(res) => Self::Ready(res),
+            S::CancelReady(res) => Self::CancelReady(res),
+            S::Played(res) => Self::Played(res),
+            S::Abort(res) => Self::Abort(res),
+            cmd => bail!("{cmd:?} has no v1 equivalent"),
+        })
+    }
+}
@@ -0,0 +1,237 @@
+use phira_mp_common::{BinaryData, BinaryReader, BinaryWriter, DecodeErrorKind};
+use std::{collections::HashMap, fmt::Debug, hash::Hash, sync::Arc};
+
+fn encode<T: BinaryData>(value: &T) -> Vec<u8> {
+    let mut data = Vec::new();
+    BinaryWriter::new(&mut data).write(value).unwrap();
+    data
+}
+
+fn round_trip<T: BinaryData + Debug + PartialEq>(value: &T) {
+    let data = encode(value);
+    assert_eq!(&BinaryReader::new(&data).decode::<T>().unwrap(), value);
+}
+
+#[derive(Debug, PartialEq, BinaryData)]
+enum Tagged {
+    Ping,
+    #[binary(tag = 5)]
+    Chat(String),
+    Ready {
+        ready: bool,
+    },
+}
+
+#[derive(Debug, PartialEq, BinaryData)]
+#[binary(tag_type = u16)]
+enum Wide {
+    Ping,
+    #[binary(tag = 300)]
+    Chat(String),
+    Ready,
+}
+
+#[test]
+fn tags() {
+    assert_eq!(encode(&Tagged::Ping), [0]);
+    assert_eq!(encode(&Tagged::Chat("a".to_owned())), [5, 1, b'a']);
+    // after a gap, counting goes on from the explicit tag
+    assert_eq!(encode(&Tagged::Ready { ready: true }), [6, 1]);
+    round_trip(&Tagged::Ping);
+    round_trip(&Tagged::Chat("a".to_owned()));
+    round_trip(&Tagged::Ready { ready: true });
+    for tag in [1, 4, 7] {
+        let err = BinaryReader::new(&[tag]).decode::<Tagged>().unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::InvalidTag(tag.into()));
+    }
+
+    assert_eq!(encode(&Wide::Ping), [0, 0]);
+    assert_eq!(encode(&Wide::Chat("a".to_owned())), [44, 1, 1, b'a']);
+    assert_eq!(encode(&Wide::Ready), [45, 1]);
+    round_trip(&Wide::Ping);
+    round_trip(&Wide::Chat("a".to_owned()));
+    round_trip(&Wide::Ready);
+    let err = BinaryReader::new(&[1, 0]).decode::<Wide>().unwrap_err();
+    assert_eq!(err.kind, DecodeErrorKind::InvalidTag(1));
+}
+
+#[derive(Debug, PartialEq, BinaryData)]
+struct Wrap<T> {
+    items: Vec<T>,
+    one: T,
+}
+
+#[derive(Debug, PartialEq, BinaryData)]
+enum Either<T> {
+    One(T),
+    Many { items: Arc<Vec<T>> },
+}
+
+#[derive(Debug, PartialEq, BinaryData)]
+struct Keyed<K, V>
+where
+    K: Eq + Hash,
+{
+    map: HashMap<K, V>,
+}
+
+/// What a crate that re-exports `phira_mp_common` would do.
+mod reexport {
+    pub use phira_mp_common as mp;
+}
+
+#[derive(Debug, PartialEq, reexport::mp::BinaryData)]
+#[binary(crate = crate::reexport::mp)]
+struct Renamed<T> {
+    value: T,
+    items: Vec<Wrap<T>>,
+}
+
+#[test]
+fn generics() {
+    round_trip(&Wrap {
+        items: vec![1u16, 2, 3],
+        one: 4u16,
+    });
+    round_trip(&Wrap {
+        items: vec![Wrap {
+            items: vec!["a".to_owned()],
+            one: "b".to_owned(),
+        }],
+        one: Wrap {
+            items: Vec::new(),
+            one: String::new(),
+        },
+    });
+    round_trip(&Either::One(1i32));
+    round_trip(&Either::Many {
+        items: Arc::new(vec![1i32, 2]),
+    });
+    round_trip(&Keyed {
+        map: HashMap::from([(1u8, "a".to_owned()), (2, "b".to_owned())]),
+    });
+}
+
+#[test]
+fn crate_path() {
+    round_trip(&Renamed {
+        value: 1u8,
+        items: vec![Wrap {
+            items: vec![2, 3],
+            one: 4,
+        }],
+    });
+}
+
+/// Writes a `u64` as a uleb, for `#[binary(with)]`.
+mod uleb {
+    use phira_mp_common::{BinaryReader, BinaryWriter};
+
+    pub fn write(value: &u64, w: &mut BinaryWriter<'_>) -> anyhow::Result<()> {
+        w.uleb(*value)
+    }
+
+    pub fn read(r: &mut BinaryReader<'_>) -> anyhow::Result<u64> {
+        r.uleb()
+    }
+}
+
+fn default_limit() -> u8 {
+    10
+}
+
+#[derive(Debug, PartialEq, BinaryData)]
+struct Versioned {
+    id: u8,
+    #[binary(since = 3)]
+    extra: u8,
+    #[binary(since = 3, default = default_limit)]
+    limit: u8,
+    name: String,
+}
+
+#[test]
+fn since() {
+    let value = Versioned {
+        id: 1,
+        extra: 2,
+        limit: 3,
+        name: "a".to_owned(),
+    };
+    for version in [1, 2] {
+        let mut data = Vec::new();
+        BinaryWriter::with_version(&mut data, version)
+            .write(&value)
+            .unwrap();
+        assert_eq!(data, [1, 1, b'a']);
+        let res = BinaryReader::with_version(&data, version).decode();
+        assert_eq!(
+            res,
+            Ok(Versioned {
+                id: 1,
+                extra: 0,
+                limit: 10,
+                name: "a".to_owned(),
+            })
+        );
+    }
+    for version in [3, 4] {
+        let mut data = Vec::new();
+        BinaryWriter::with_version(&mut data, version)
+            .write(&value)
+            .unwrap();
+        assert_eq!(data, [1, 2, 3, 1, b'a']);
+        let res = BinaryReader::with_version(&data, version).decode();
+        assert_eq!(res.as_ref(), Ok(&value));
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Cache(Option<String>);
+
+#[derive(Debug, PartialEq, BinaryData)]
+struct Tuple(
+    #[binary(with = uleb)] u64,
+    #[binary(skip)] Cache,
+    #[binary(default = default_limit)] u8,
+    i8,
+);
+
+#[derive(Debug, PartialEq, BinaryData)]
+enum Fields {
+    Tuple(#[binary(skip)] Cache, #[binary(with = uleb)] u64),
+    Named {
+        #[binary(default)]
+        cache: Cache,
+        #[binary(with = uleb)]
+        id: u64,
+    },
+}
+
+#[test]
+fn with_and_skip() {
+    let value = Tuple(300, Cache(Some("a".to_owned())), 3, -1);
+    let data = encode(&value);
+    assert_eq!(data, [0xac, 0x02, 0xff]);
+    let res = BinaryReader::new(&data).decode();
+    assert_eq!(res, Ok(Tuple(300, Cache(None), 10, -1)));
+
+    let data = encode(&Fields::Tuple(Cache(Some("a".to_owned())), 300));
+    assert_eq!(data, [0, 0xac, 0x02]);
+    let res = BinaryReader::new(&data).decode();
+    assert_eq!(res, Ok(Fields::Tuple(Cache(None), 300)));
+
+    let data = encode(&Fields::Named {
+        cache: Cache(Some("a".to_owned())),
+        id: 1,
+    });
+    assert_eq!(data, [1, 1]);
+    let res = BinaryReader::new(&data).decode();
+    assert_eq!(
+        res,
+        Ok(Fields::Named {
+            cache: Cache(None),
+            id: 1
+        })
+    );
+}
@@ -0,0 +1,13 @@
+use phira_mp_common::BinaryData;
+
+#[derive(BinaryData)]
+enum Command {
+    Ping,
+    #[binary(tag = 2)]
+    Chat(String),
+    Ready,
+    #[binary(tag = 3)]
+    Leave,
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+use phira_mp_common::BinaryData;
+
+#[derive(BinaryData)]
+enum Command {
+    Ping,
+    #[binary(tag = 256)]
+    Chat(String),
+}
+
+#[derive(BinaryData)]
+#[binary(tag_type = u16)]
+enum Wide {
+    #[binary(tag = 65535)]
+    Ping,
+    Chat(String),
+}
+
+fn main() {}
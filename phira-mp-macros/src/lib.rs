@@ -1,14 +1,28 @@
 use proc_macro2::{Ident, Span, TokenStream};
 use quote::quote;
+use std::collections::HashMap;
 use syn::{
-    Data, DataEnum, DataStruct, DeriveInput, Fields, GenericArgument, PathArguments, Type, Variant,
-    parse_macro_input,
+    Attribute, Data, DataEnum, DataStruct, DeriveInput, Error, Fields, GenericArgument, Generics,
+    Index, LitInt, Path, PathArguments, Token, Type, Variant, parse_macro_input, parse_quote,
 };
 
-#[proc_macro_derive(BinaryData)]
+/// Enum variants are tagged in declaration order, starting from 0, unless
+/// given `#[binary(tag = N)]`; a variant without one takes the previous tag
+/// plus one. Tags are `u8`, or whatever `#[binary(tag_type = u16)]` on the
+/// enum says (`u8`, `u16` or `u32`).
+///
+/// Fields take `#[binary(skip)]`, `#[binary(default)]`,
+/// `#[binary(default = path)]`, `#[binary(with = path)]` and
+/// `#[binary(since = N)]`, see [`FieldAttrs`].
+///
+/// Generated code refers to `::phira_mp_common`; crates that re-export it
+/// under another name can say so with `#[binary(crate = path)]`. Type
+/// parameters are required to be `BinaryData + 'static` themselves, the
+/// latter for `DecodeLimits` to tell their collections apart.
+#[proc_macro_derive(BinaryData, attributes(binary))]
 pub fn derive_model_ex(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
-    let res = build_derive(input.ident, input.data);
+    let res = build_derive(input).unwrap_or_else(Error::into_compile_error);
     quote! {
         #res
     }
@@ -20,187 +34,348 @@ struct TypeInfo {
     is_vec: bool,
 }
 
-fn parse_type(typ: &Type) -> TypeInfo {
-    let (typ, is_arc) = match typ {
-        Type::Path(path) => {
-            let last = path.path.segments.last().unwrap();
-            if last.ident == "Arc" {
-                (
-                    match &last.arguments {
-                        PathArguments::AngleBracketed(arg) => match &arg.args[0] {
-                            GenericArgument::Type(typ) => typ,
-                            _ => unreachable!(),
-                        },
-                        _ => unreachable!(),
-                    },
-                    true,
-                )
+/// `T` if `typ` is `wrapper<T>`.
+fn unwrap_type<'a>(typ: &'a Type, wrapper: &str) -> syn::Result<Option<&'a Type>> {
+    let Type::Path(path) = typ else {
+        return Ok(None);
+    };
+    let Some(last) = path.path.segments.last() else {
+        return Ok(None);
+    };
+    if last.ident != wrapper {
+        return Ok(None);
+    }
+    if let PathArguments::AngleBracketed(arg) = &last.arguments
+        && let Some(GenericArgument::Type(typ)) = arg.args.first()
+    {
+        return Ok(Some(typ));
+    }
+    Err(Error::new_spanned(
+        typ,
+        format!("expected `{wrapper}<T>` with a type argument"),
+    ))
+}
+
+fn parse_type(typ: &Type) -> syn::Result<TypeInfo> {
+    let (typ, is_arc) = match unwrap_type(typ, "Arc")? {
+        Some(inner) => (inner, true),
+        None => (typ, false),
+    };
+    let is_vec = unwrap_type(typ, "Vec")?.is_some();
+    Ok(TypeInfo { is_arc, is_vec })
+}
+
+/// What `#[binary(..)]` says about the type itself.
+struct ContainerAttrs {
+    krate: Path,
+    tag_type: Ident,
+}
+
+fn container_attrs(attrs: &[Attribute]) -> syn::Result<ContainerAttrs> {
+    let mut res = ContainerAttrs {
+        krate: parse_quote!(::phira_mp_common),
+        tag_type: Ident::new("u8", Span::call_site()),
+    };
+    for attr in attrs.iter().filter(|it| it.path().is_ident("binary")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("crate") {
+                res.krate = meta.value()?.parse()?;
+            } else if meta.path.is_ident("tag_type") {
+                res.tag_type = meta.value()?.parse()?;
             } else {
-                (typ, false)
+                return Err(meta.error("unknown container attribute"));
             }
+            Ok(())
+        })?;
+    }
+    Ok(res)
+}
+
+fn build_derive(input: DeriveInput) -> syn::Result<TokenStream> {
+    let attrs = container_attrs(&input.attrs)?;
+    let krate = &attrs.krate;
+    let (read, write) = match input.data {
+        Data::Struct(DataStruct { fields, .. }) => build_derive_struct(fields)?,
+        Data::Enum(DataEnum { variants, .. }) => {
+            build_derive_enum(&attrs.tag_type, variants.into_iter().collect())?
+        }
+        Data::Union(data) => {
+            return Err(Error::new_spanned(
+                data.union_token,
+                "BinaryData can't be derived for unions",
+            ));
         }
-        _ => (typ, false),
     };
-    let (_typ, is_vec) = match typ {
-        Type::Path(path) => {
-            let last = path.path.segments.last().unwrap();
-            if last.ident == "Vec" {
-                (
-                    match &last.arguments {
-                        PathArguments::AngleBracketed(arg) => match &arg.args[0] {
-                            GenericArgument::Type(typ) => typ,
-                            _ => unreachable!(),
-                        },
-                        _ => unreachable!(),
-                    },
-                    true,
-                )
-            } else {
-                (typ, false)
+
+    let mut generics: Generics = input.generics;
+    let params: Vec<_> = generics.type_params().map(|it| it.ident.clone()).collect();
+    let where_clause = generics.make_where_clause();
+    for param in params {
+        where_clause
+            .predicates
+            .push(parse_quote!(#param: #krate::BinaryData + 'static));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let name = input.ident;
+    let label = name.to_string();
+    Ok(quote! {
+        impl #impl_generics #krate::BinaryData for #name #ty_generics #where_clause {
+            fn read_binary(
+                r: &mut #krate::BinaryReader<'_>,
+            ) -> #krate::__private::Result<Self> {
+                r.ty(#label, |r| { #read })
+            }
+
+            fn write_binary(
+                &self,
+                w: &mut #krate::BinaryWriter<'_>,
+            ) -> #krate::__private::Result<()> {
+                #write
+                Ok(())
             }
         }
-        _ => (typ, false),
-    };
-    TypeInfo { is_arc, is_vec }
+    })
 }
 
-fn build_derive(name: Ident, data: Data) -> TokenStream {
-    match data {
-        Data::Struct(DataStruct { fields, .. }) => build_derive_struct(name, fields),
-        Data::Enum(DataEnum { variants, .. }) => {
-            build_derive_enum(name, variants.into_iter().collect())
+/// The largest tag `tag_type` can hold.
+fn max_tag(tag_type: &Ident) -> syn::Result<u64> {
+    Ok(match tag_type.to_string().as_str() {
+        "u8" => u8::MAX as u64,
+        "u16" => u16::MAX as u64,
+        "u32" => u32::MAX as u64,
+        _ => {
+            return Err(Error::new_spanned(
+                tag_type,
+                "tag type must be u8, u16 or u32",
+            ));
         }
-        _ => panic!(),
-    }
+    })
 }
 
-fn build_derive_struct(name: Ident, fields: Fields) -> TokenStream {
-    let fields: Vec<_> = fields
+/// `#[binary(tag = N)]` of a variant.
+fn variant_tag(variant: &Variant) -> syn::Result<Option<LitInt>> {
+    let mut tag = None;
+    for attr in variant
+        .attrs
         .iter()
-        .map(|it| (it.ident.clone(), parse_type(&it.ty)))
-        .collect();
-    let read = struct_read(&fields);
-    let write = struct_write(&fields, true);
-    quote! {
-        impl crate::BinaryData for #name {
-            fn read_binary(r: &mut crate::BinaryReader<'_>) -> Result<Self> {
-                Ok(Self { #read })
-            }
-
-            fn write_binary(&self, w: &mut crate::BinaryWriter<'_>) -> Result<()> {
-                #write
+        .filter(|it| it.path().is_ident("binary"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("tag") {
+                tag = Some(meta.value()?.parse()?);
                 Ok(())
+            } else {
+                Err(meta.error("unknown variant attribute"))
             }
+        })?;
+    }
+    Ok(tag)
+}
+
+/// Wire tag of each variant, see [`derive_model_ex`].
+fn variant_tags(tag_type: &Ident, variants: &[Variant]) -> syn::Result<Vec<u64>> {
+    let max = max_tag(tag_type)?;
+    let mut seen = HashMap::new();
+    let mut next = 0;
+    let mut tags = Vec::with_capacity(variants.len());
+    for variant in variants {
+        let tag = match variant_tag(variant)? {
+            Some(lit) => lit.base10_parse()?,
+            None => next,
+        };
+        if tag > max {
+            return Err(Error::new_spanned(
+                variant,
+                format!("tag {tag} doesn't fit in {tag_type}, try a wider #[binary(tag_type)]"),
+            ));
+        }
+        if let Some(prev) = seen.insert(tag, &variant.ident) {
+            return Err(Error::new_spanned(
+                variant,
+                format!("tag {tag} is already used by {prev}"),
+            ));
         }
+        tags.push(tag);
+        next = tag + 1;
+    }
+    Ok(tags)
+}
+
+/// What `#[binary(..)]` says about a field.
+///
+/// - `skip`, or `default`: not on the wire, decoded as `Default::default()`.
+/// - `default = path`: not on the wire, decoded as `path()`.
+/// - `with = path`: encoded by `path::write(&value, w)` and decoded by
+///   `path::read(r)` instead of `BinaryData`.
+/// - `since = N`: only on the wire when the reader's or writer's version is
+///   at least `N`, decoded as the default otherwise.
+#[derive(Default)]
+struct FieldAttrs {
+    skip: bool,
+    default: Option<Path>,
+    with: Option<Path>,
+    since: Option<LitInt>,
+}
+
+fn field_attrs(attrs: &[Attribute]) -> syn::Result<FieldAttrs> {
+    let mut res = FieldAttrs::default();
+    let mut default = false;
+    for attr in attrs.iter().filter(|it| it.path().is_ident("binary")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                res.skip = true;
+            } else if meta.path.is_ident("default") {
+                if meta.input.peek(Token![=]) {
+                    res.default = Some(meta.value()?.parse()?);
+                }
+                default = true;
+            } else if meta.path.is_ident("with") {
+                res.with = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("since") {
+                let since: LitInt = meta.value()?.parse()?;
+                since.base10_parse::<u8>()?;
+                res.since = Some(since);
+            } else {
+                return Err(meta.error("unknown field attribute"));
+            }
+            Ok(())
+        })?;
     }
+    // with `since`, `default` only supplies the fallback
+    res.skip |= default && res.since.is_none();
+    Ok(res)
 }
 
-fn build_derive_enum(name: Ident, variants: Vec<Variant>) -> TokenStream {
-    let read_arms = variants
+struct FieldInfo {
+    name: Option<Ident>,
+    typ: TypeInfo,
+    attrs: FieldAttrs,
+}
+
+fn parse_fields(fields: &Fields) -> syn::Result<Vec<FieldInfo>> {
+    fields
+        .iter()
+        .map(|it| {
+            Ok(FieldInfo {
+                name: it.ident.clone(),
+                typ: parse_type(&it.ty)?,
+                attrs: field_attrs(&it.attrs)?,
+            })
+        })
+        .collect()
+}
+
+/// Names the fields of a variant are bound to when matching on it. Fields
+/// that aren't written are left out.
+fn field_bindings(fields: &[FieldInfo]) -> Vec<TokenStream> {
+    fields
         .iter()
         .enumerate()
-        .map(|(i, it)| {
-            let i = i as u8;
-            let name = &it.ident;
-            match &it.fields {
-                Fields::Unit => quote! { #i => Self::#name },
-                Fields::Unnamed(fields) => {
-                    let fields = struct_read(
-                        &fields
-                            .unnamed
-                            .iter()
-                            .map(|it| (None, parse_type(&it.ty)))
-                            .collect::<Vec<_>>(),
-                    );
-                    quote! { #i => Self::#name(#fields) }
-                }
-                Fields::Named(fields) => {
-                    let fields = struct_read(
-                        &fields
-                            .named
-                            .iter()
-                            .map(|it| (it.ident.clone(), parse_type(&it.ty)))
-                            .collect::<Vec<_>>(),
-                    );
-                    quote! { #i => Self::#name { #fields } }
-                }
+        .map(|(i, field)| {
+            let binding = Ident::new(&format!("_{i}"), Span::call_site());
+            match (&field.name, field.attrs.skip) {
+                (Some(name), true) => quote! { #name: _ },
+                (Some(name), false) => quote! { #name },
+                (None, true) => quote! { _ },
+                (None, false) => quote! { #binding },
             }
         })
-        .chain(std::iter::once(
-            quote! { x => anyhow::bail!("invalid enum: {}", x) },
-        ));
-    let write_arms = variants.iter().enumerate().map(|(i, it)| {
-        let i = i as u8;
+        .collect()
+}
+
+/// Bodies of `read_binary` and `write_binary` for a struct.
+fn build_derive_struct(fields: Fields) -> syn::Result<(TokenStream, TokenStream)> {
+    let is_tuple = matches!(fields, Fields::Unnamed(_));
+    let fields = parse_fields(&fields)?;
+    let read = struct_read(&fields);
+    let read = if is_tuple {
+        quote! { Ok(Self(#read)) }
+    } else {
+        quote! { Ok(Self { #read }) }
+    };
+    Ok((read, struct_write(&fields, true)))
+}
+
+/// Bodies of `read_binary` and `write_binary` for an enum.
+fn build_derive_enum(
+    tag_type: &Ident,
+    variants: Vec<Variant>,
+) -> syn::Result<(TokenStream, TokenStream)> {
+    let tags: Vec<_> = variant_tags(tag_type, &variants)?
+        .into_iter()
+        .map(proc_macro2::Literal::u64_unsuffixed)
+        .collect();
+    let mut read_arms = Vec::with_capacity(variants.len() + 1);
+    let mut write_arms = Vec::with_capacity(variants.len());
+    for (it, i) in variants.iter().zip(&tags) {
         let name = &it.ident;
+        let label = name.to_string();
+        let fields = parse_fields(&it.fields)?;
+        let read = struct_read(&fields);
+        let writes = struct_write(&fields, false);
+        let bindings = field_bindings(&fields);
         match &it.fields {
-            Fields::Unit => quote! { Self::#name => w.write_val(#i)? },
-            Fields::Unnamed(fields) => {
-                let names: Vec<_> = (0..fields.unnamed.len())
-                    .map(|i| {
-                        let ident = Ident::new(&format!("_{i}"), Span::call_site());
-                        quote! { #ident }
-                    })
-                    .collect();
-                let writes = struct_write(
-                    &fields
-                        .unnamed
-                        .iter()
-                        .map(|it| (None, parse_type(&it.ty)))
-                        .collect::<Vec<_>>(),
-                    false,
-                );
-                quote! { Self::#name(#(#names,)*) => { w.write_val(#i)?; #writes } }
-            }
-            Fields::Named(fields) => {
-                let names: Vec<_> = fields
-                    .named
-                    .iter()
-                    .map(|it| {
-                        let ident = it.ident.clone().unwrap();
-                        quote! { #ident }
-                    })
-                    .collect();
-                let writes = struct_write(
-                    &fields
-                        .named
-                        .iter()
-                        .map(|it| (it.ident.clone(), parse_type(&it.ty)))
-                        .collect::<Vec<_>>(),
-                    false,
-                );
-                quote! { Self::#name { #(#names,)* } => { w.write_val(#i)?; #writes } }
+            Fields::Unit => {
+                read_arms.push(quote! { #i => Self::#name });
+                write_arms.push(quote! { Self::#name => w.write_val::<#tag_type>(#i)? });
             }
-        }
-    });
-    quote! {
-        impl crate::BinaryData for #name {
-            fn read_binary(r: &mut crate::BinaryReader<'_>) -> Result<Self> {
-                Ok(match r.read::<u8>()? {
-                    #(#read_arms,)*
-                })
+            Fields::Unnamed(_) => {
+                read_arms.push(quote! { #i => r.variant(#label, |r| Ok(Self::#name(#read)))? });
+                write_arms.push(quote! {
+                    Self::#name(#(#bindings,)*) => { w.write_val::<#tag_type>(#i)?; #writes }
+                });
             }
-
-            fn write_binary(&self, w: &mut crate::BinaryWriter<'_>) -> Result<()> {
-                match self {
-                    #(#write_arms,)*
-                }
-                Ok(())
+            Fields::Named(_) => {
+                read_arms.push(quote! { #i => r.variant(#label, |r| Ok(Self::#name { #read }))? });
+                write_arms.push(quote! {
+                    Self::#name { #(#bindings,)* } => { w.write_val::<#tag_type>(#i)?; #writes }
+                });
             }
         }
     }
+    read_arms.push(quote! { x => return Err(r.invalid_tag::<#tag_type>(x)) });
+    let read = quote! {
+        Ok(match r.read::<#tag_type>()? {
+            #(#read_arms,)*
+        })
+    };
+    let write = quote! {
+        match self {
+            #(#write_arms,)*
+        }
+    };
+    Ok((read, write))
 }
 
-fn struct_read(fields: &[(Option<Ident>, TypeInfo)]) -> TokenStream {
-    let fields = fields.iter().map(|(name, typ)| field_read(name, typ));
+fn struct_read(fields: &[FieldInfo]) -> TokenStream {
+    let fields = fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| field_read(i, field));
     quote! { #(#fields,)* }
 }
 
-fn field_read(name: &Option<Ident>, typ: &TypeInfo) -> TokenStream {
-    let val = match (typ.is_arc, typ.is_vec) {
-        (false, false) => quote! { r.read()? },
-        (false, true) => quote! { r.array()? },
-        (true, false) => quote! { r.read()?.into() },
-        (true, true) => quote! { r.array()?.into() },
+fn field_read(index: usize, field: &FieldInfo) -> TokenStream {
+    let FieldInfo { name, typ, attrs } = field;
+    let label = name
+        .as_ref()
+        .map_or_else(|| index.to_string(), ToString::to_string);
+    let default = match &attrs.default {
+        Some(path) => quote! { #path() },
+        None => quote! { ::core::default::Default::default() },
+    };
+    let val = match (&attrs.with, typ.is_arc, typ.is_vec) {
+        (Some(with), ..) => quote! { #with::read(r) },
+        (None, false, false) => quote! { r.read() },
+        (None, false, true) => quote! { r.array() },
+        (None, true, false) => quote! { Ok(r.read()?.into()) },
+        (None, true, true) => quote! { Ok(r.array()?.into()) },
+    };
+    let val = quote! { r.field(#label, |r| #val)? };
+    let val = match &attrs.since {
+        _ if attrs.skip => default,
+        Some(since) => quote! { if r.version() >= #since { #val } else { #default } },
+        None => val,
     };
     if let Some(name) = name {
         quote! { #name: #val }
@@ -209,35 +384,42 @@ fn field_read(name: &Option<Ident>, typ: &TypeInfo) -> TokenStream {
     }
 }
 
-fn struct_write(fields: &[(Option<Ident>, TypeInfo)], use_self: bool) -> TokenStream {
+fn struct_write(fields: &[FieldInfo], use_self: bool) -> TokenStream {
     fields
         .iter()
         .enumerate()
-        .map(|(i, (name, typ))| {
+        .map(|(i, field)| {
             field_write(
                 if use_self {
-                    let i = i as u8;
-                    if let Some(name) = name {
+                    if let Some(name) = &field.name {
                         quote! { &self.#name }
                     } else {
+                        let i = Index::from(i);
                         quote! { &self.#i }
                     }
                 } else {
-                    let name = name
+                    let name = field
+                        .name
                         .clone()
                         .unwrap_or(Ident::new(&format!("_{i}"), Span::call_site()));
                     quote! { #name }
                 },
-                typ,
+                field,
             )
         })
         .collect()
 }
 
-fn field_write(field: TokenStream, typ: &TypeInfo) -> TokenStream {
-    if typ.is_vec {
-        quote! { w.array(#field)?; }
-    } else {
-        quote! { w.write(#field)?; }
+fn field_write(value: TokenStream, field: &FieldInfo) -> TokenStream {
+    let FieldInfo { typ, attrs, .. } = field;
+    let write = match &attrs.with {
+        Some(with) => quote! { #with::write(#value, w)?; },
+        None if typ.is_vec => quote! { w.array(#value)?; },
+        None => quote! { w.write(#value)?; },
+    };
+    match &attrs.since {
+        _ if attrs.skip => quote! {},
+        Some(since) => quote! { if w.version() >= #since { #write } },
+        None => write,
     }
 }
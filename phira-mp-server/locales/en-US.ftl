@@ -4,6 +4,24 @@ create-id-occupied = Room ID is occupied
 join-game-ongoing = Game is ongoing
 join-room-full = Room is full
 join-room-locked = Room is locked
+join-banned = You are banned from this room
+join-wrong-password = Wrong password
 join-cant-monitor = Permission denied. You can't monitor this room.
 
 start-no-chart-selected = No chart selected
+
+ready-timeout-cancel = Not everyone got ready in time, the game was cancelled
+ready-timeout-sit-out = { $name } wasn't ready in time and sits this game out
+play-timeout = { $name } didn't finish in time and was aborted
+
+ranking-metric = { $metric ->
+    [accuracy] accuracy
+    [max-combo] max combo
+    [std] timing deviation
+   *[score] score
+}
+legacy-ranking-metric = Players are now ranked by { $metric }
+legacy-ready-countdown = { $seconds } seconds left to get ready
+legacy-ready-timeout-cancel = If not everyone gets ready in time, the game will be cancelled
+legacy-ready-timeout-start-without = If not everyone gets ready in time, the game will start without them
+legacy-game-results = Results by { $metric }: { $ranking }
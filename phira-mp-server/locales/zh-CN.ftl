@@ -4,6 +4,24 @@ create-id-occupied = 房间 ID 已被占用
 join-game-ongoing = 游戏正在进行中
 join-room-full = 房间已满
 join-room-locked = 房间已锁定
+join-banned = 你已被禁止加入该房间
+join-wrong-password = 密码错误
 join-cant-monitor = 权限不足，不能旁观房间
 
 start-no-chart-selected = 还没有选择谱面
+
+ready-timeout-cancel = 有玩家未能按时准备，游戏已取消
+ready-timeout-sit-out = { $name } 未能按时准备，本局不参与游戏
+play-timeout = { $name } 未能按时完成游戏，已自动放弃
+
+ranking-metric = { $metric ->
+    [accuracy] 准确率
+    [max-combo] 最大连击
+    [std] 时间偏差
+   *[score] 分数
+}
+legacy-ranking-metric = 排名依据已改为{ $metric }
+legacy-ready-countdown = 还有 { $seconds } 秒的准备时间
+legacy-ready-timeout-cancel = 若有玩家未能按时准备，游戏将被取消
+legacy-ready-timeout-start-without = 若有玩家未能按时准备，游戏将在他们缺席的情况下开始
+legacy-game-results = 按{ $metric }排名：{ $ranking }
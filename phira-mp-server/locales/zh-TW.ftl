@@ -4,6 +4,24 @@ create-id-occupied = 房間 ID 已被佔用
 join-game-ongoing = 遊戲正在進行中
 join-room-full = 房間已滿
 join-room-locked = 房間已鎖定
+join-banned = 你已被禁止加入該房間
+join-wrong-password = 密碼錯誤
 join-cant-monitor = 權限不足，不能旁觀房間
 
 start-no-chart-selected = 還沒有選擇譜面
+
+ready-timeout-cancel = 有玩家未能按時準備，遊戲已取消
+ready-timeout-sit-out = { $name } 未能按時準備，本局不參與遊戲
+play-timeout = { $name } 未能按時完成遊戲，已自動放棄
+
+ranking-metric = { $metric ->
+    [accuracy] 準確率
+    [max-combo] 最大連擊
+    [std] 時間偏差
+   *[score] 分數
+}
+legacy-ranking-metric = 排名依據已改為{ $metric }
+legacy-ready-countdown = 還有 { $seconds } 秒的準備時間
+legacy-ready-timeout-cancel = 若有玩家未能按時準備，遊戲將被取消
+legacy-ready-timeout-start-without = 若有玩家未能按時準備，遊戲將在他們缺席的情況下開始
+legacy-game-results = 按{ $metric }排名：{ $ranking }
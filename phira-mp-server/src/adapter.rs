@@ -0,0 +1,136 @@
+use crate::{User, tl};
+use anyhow::{Result, bail};
+use phira_mp_common::{
+    BinaryCodec, BinaryReader, BinaryWriter, Capabilities, ClientCommand, Codec, DecodeLimits,
+    JudgeEvent, LEGACY_VERSION, Message, PROTOCOL_VERSION, Protocol, RankingMetric,
+    ReadyTimeoutAction, SYSTEM_USER, ServerCommand, TouchFrame, v1,
+};
+use std::sync::Arc;
+
+/// What a client may make us decode. Touches and judges are batched per
+/// frame and come in larger numbers than anything else.
+fn limits() -> DecodeLimits {
+    DecodeLimits::default()
+        .with_max_elements::<TouchFrame>(4096)
+        .with_max_elements::<JudgeEvent>(4096)
+}
+
+/// Pick the wire layout for a session that negotiated `protocol`.
+/// Whatever the version, clients are held to [`limits`]. Only clients that
+/// went through the handshake are decoded strictly; legacy ones were never
+/// asked to write canonical data and some of them don't.
+pub fn codec(protocol: &Protocol) -> Result<Arc<dyn Codec<ServerCommand, ClientCommand>>> {
+    Ok(match protocol.version {
+        PROTOCOL_VERSION => Arc::new(
+            BinaryCodec::new(protocol.version)
+                .with_limits(limits())
+                .strict(),
+        ),
+        LEGACY_VERSION => Arc::new(V1Codec { limits: limits() }),
+        version => bail!("unsupported protocol version {version}"),
+    })
+}
+
+struct V1Codec {
+    limits: DecodeLimits,
+}
+
+impl Codec<ServerCommand, ClientCommand> for V1Codec {
+    fn encode(&self, payload: &ServerCommand, vec: &mut Vec<u8>) -> Result<()> {
+        BinaryWriter::with_version(vec, LEGACY_VERSION)
+            .write(&v1::ServerCommand::try_from(payload.clone())?)
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<ClientCommand> {
+        Ok(BinaryReader::with_version(data, LEGACY_VERSION)
+            .with_limits(&self.limits)
+            .decode::<v1::ClientCommand>()?
+            .into())
+    }
+}
+
+fn metric_name(metric: RankingMetric) -> String {
+    let metric = match metric {
+        RankingMetric::Score => "score",
+        RankingMetric::Accuracy => "accuracy",
+        RankingMetric::MaxCombo => "max-combo",
+        RankingMetric::Std => "std",
+    };
+    tl!("ranking-metric", "metric" => metric)
+}
+
+/// Rewrite `msg` for a session that can't understand it. Messages beyond
+/// the legacy set become chat lines from [`SYSTEM_USER`]; `None` means there
+/// is nothing worth telling.
+///
+/// Must run in the recipient's [`LANGUAGE`](crate::l10n::LANGUAGE) scope.
+pub async fn downgrade(protocol: &Protocol, user: &User, msg: Message) -> Option<Message> {
+    let content = match msg {
+        Message::StartAt { .. } if protocol.supports(Capabilities::CLOCK_SYNC) => return Some(msg),
+        _ if protocol.supports(Capabilities::EXTENDED_MESSAGES) => return Some(msg),
+
+        // they start on `StartPlaying` instead
+        Message::StartAt { .. } => return None,
+        // the room announces it in chat already
+        Message::SitOut { .. } => return None,
+
+        // all they need to know is that `user` is gone
+        Message::KickUser { user, name } | Message::BanUser { user, name } => {
+            return Some(Message::LeaveRoom { user, name });
+        }
+        Message::RankingMetric { metric } => {
+            tl!("legacy-ranking-metric", "metric" => metric_name(metric))
+        }
+        Message::ReadyCountdown { seconds } => {
+            tl!("legacy-ready-countdown", "seconds" => seconds.round() as i64)
+        }
+        Message::ReadyTimeoutAction { action } => match action {
+            ReadyTimeoutAction::Cancel => tl!("legacy-ready-timeout-cancel"),
+            ReadyTimeoutAction::StartWithout => tl!("legacy-ready-timeout-start-without"),
+        }
+        .into_owned(),
+        Message::GameResults {
+            metric, ranking, ..
+        } => {
+            let users = user.server.users.read().await;
+            let ranking = ranking
+                .iter()
+                .map(|it| {
+                    let name = users
+                        .get(&it.user)
+                        .map_or_else(|| format!("#{}", it.user), |user| user.name.clone());
+                    format!(
+                        "{}. {name} {} ({:.2}%)",
+                        it.rank,
+                        it.score,
+                        it.accuracy * 100.
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            tl!("legacy-game-results", "metric" => metric_name(metric), "ranking" => ranking)
+        }
+
+        // no wildcard here, new messages need a legacy rendering
+        msg @ (Message::Chat { .. }
+        | Message::CreateRoom { .. }
+        | Message::JoinRoom { .. }
+        | Message::LeaveRoom { .. }
+        | Message::NewHost { .. }
+        | Message::SelectChart { .. }
+        | Message::GameStart { .. }
+        | Message::Ready { .. }
+        | Message::CancelReady { .. }
+        | Message::CancelGame { .. }
+        | Message::StartPlaying
+        | Message::Played { .. }
+        | Message::GameEnd
+        | Message::Abort { .. }
+        | Message::LockRoom { .. }
+        | Message::CycleRoom { .. }) => return Some(msg),
+    };
+    Some(Message::Chat {
+        user: SYSTEM_USER,
+        content,
+    })
+}
@@ -0,0 +1,130 @@
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs::File,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthUser {
+    pub id: i32,
+    pub name: String,
+    #[serde(default)]
+    pub language: String,
+}
+
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, token: &str) -> Result<AuthUser>;
+}
+
+/// Which [`Authenticator`] the server uses, as written in `server_config.yml`:
+///
+/// ```yaml
+/// auth:
+///   type: static
+///   path: users.yml
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum AuthConfig {
+    #[default]
+    Phira,
+    Static {
+        path: PathBuf,
+    },
+    Dev,
+}
+
+impl AuthConfig {
+    pub fn build(&self, api: &str) -> Result<Box<dyn Authenticator>> {
+        Ok(match self {
+            Self::Phira => Box::new(PhiraAuthenticator::new(api)),
+            Self::Static { path } => Box::new(StaticAuthenticator::load(path)?),
+            Self::Dev => Box::new(DevAuthenticator),
+        })
+    }
+}
+
+/// Asks the Phira API who owns the token.
+pub struct PhiraAuthenticator {
+    client: reqwest::Client,
+    host: String,
+}
+
+impl PhiraAuthenticator {
+    pub fn new(host: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            host: host.trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl Authenticator for PhiraAuthenticator {
+    async fn authenticate(&self, token: &str) -> Result<AuthUser> {
+        Ok(self
+            .client
+            .get(format!("{}/me", self.host))
+            .header(reqwest::header::AUTHORIZATION, format!("Bearer {token}"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+}
+
+/// Looks tokens up in a YAML map of `token: { id, name, language }`.
+pub struct StaticAuthenticator {
+    users: HashMap<String, AuthUser>,
+}
+
+impl StaticAuthenticator {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open user file {}", path.display()))?;
+        let users = serde_yaml::from_reader(file)
+            .with_context(|| format!("failed to parse user file {}", path.display()))?;
+        Ok(Self { users })
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticAuthenticator {
+    async fn authenticate(&self, token: &str) -> Result<AuthUser> {
+        match self.users.get(token) {
+            Some(user) => Ok(user.clone()),
+            None => bail!("unknown token"),
+        }
+    }
+}
+
+/// Accepts any token. A numeric token is used as the user id, anything else
+/// is hashed into one; the token doubles as the user name.
+///
+/// Never use this on a public server.
+pub struct DevAuthenticator;
+
+#[async_trait]
+impl Authenticator for DevAuthenticator {
+    async fn authenticate(&self, token: &str) -> Result<AuthUser> {
+        if token.is_empty() {
+            bail!("empty token");
+        }
+        let id = token.parse().unwrap_or_else(|_| {
+            let mut hasher = DefaultHasher::new();
+            token.hash(&mut hasher);
+            (hasher.finish() & i32::MAX as u64) as i32
+        });
+        Ok(AuthUser {
+            id,
+            name: token.to_owned(),
+            language: String::new(),
+        })
+    }
+}
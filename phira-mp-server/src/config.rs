@@ -0,0 +1,180 @@
+use crate::{AuthConfig, ChartConfig, RecordConfig};
+use anyhow::{Context, Result, bail, ensure};
+use phira_mp_common::HEARTBEAT_DISCONNECT_TIMEOUT;
+use serde::{Deserialize, Deserializer};
+use std::{
+    fmt::Display,
+    fs::File,
+    net::{IpAddr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tracing::metadata::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+pub const DEFAULT_CONFIG_PATH: &str = "server_config.yml";
+const HOST: &str = "https://phira.5wyxi.com";
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub dir: PathBuf,
+    /// Level of the hourly log files.
+    #[serde(deserialize_with = "from_str")]
+    pub file_level: LevelFilter,
+    /// `RUST_LOG`-style filter for stdout. Falls back to `RUST_LOG` if unset.
+    pub stdout: Option<String>,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            dir: "log".into(),
+            file_level: LevelFilter::DEBUG,
+            stdout: None,
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn stdout_filter(&self) -> Result<EnvFilter> {
+        Ok(match &self.stdout {
+            Some(directives) => EnvFilter::try_new(directives)
+                .with_context(|| format!("invalid stdout log filter {directives:?}"))?,
+            None => EnvFilter::from_default_env(),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    /// Base URL of the Phira API.
+    pub api: String,
+
+    pub monitors: Vec<i32>,
+    pub room_max_users: usize,
+
+    pub auth: AuthConfig,
+    pub charts: ChartConfig,
+    pub records: RecordConfig,
+    /// Where finished games are appended, as JSON Lines, e.g.
+    /// `history.jsonl`. Disabled unless set.
+    pub history: Option<PathBuf>,
+
+    /// Connections that haven't authenticated within this are dropped.
+    #[serde(with = "humantime_serde")]
+    pub auth_timeout: Duration,
+    /// Sessions that haven't sent anything within this are considered lost.
+    #[serde(with = "humantime_serde")]
+    pub heartbeat_timeout: Duration,
+    /// How long a disconnected user keeps their place in the room.
+    #[serde(with = "humantime_serde")]
+    pub dangle_timeout: Duration,
+    /// Same, for players who disconnect mid-game. They can reconnect and
+    /// still submit their result within this.
+    #[serde(with = "humantime_serde")]
+    pub reconnect_grace: Duration,
+    /// How long everyone has to get ready once the host starts a game.
+    #[serde(with = "humantime_serde")]
+    pub ready_timeout: Duration,
+    /// How far ahead of time the synchronized start is scheduled.
+    #[serde(with = "humantime_serde")]
+    pub start_lead: Duration,
+    /// Assumed length of charts whose duration is unknown.
+    #[serde(with = "humantime_serde")]
+    pub default_chart_length: Duration,
+    /// Extra time after the chart ends before unfinished players are aborted.
+    #[serde(with = "humantime_serde")]
+    pub play_grace: Duration,
+
+    pub log: LogConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addresses: vec![Ipv6Addr::UNSPECIFIED.into()],
+            port: 12346,
+            api: HOST.to_owned(),
+
+            monitors: vec![2],
+            room_max_users: 8,
+
+            auth: AuthConfig::default(),
+            charts: ChartConfig::default(),
+            records: RecordConfig::default(),
+            history: None,
+
+            auth_timeout: Duration::from_secs(10),
+            heartbeat_timeout: HEARTBEAT_DISCONNECT_TIMEOUT,
+            dangle_timeout: Duration::from_secs(10),
+            reconnect_grace: Duration::from_secs(30),
+            ready_timeout: Duration::from_secs(60),
+            start_lead: Duration::from_secs(2),
+            default_chart_length: Duration::from_secs(5 * 60),
+            play_grace: Duration::from_secs(60),
+
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Load the config from `path`, which must exist. Without a path,
+    /// [`DEFAULT_CONFIG_PATH`] is used if present and defaults otherwise.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None => {
+                let path = Path::new(DEFAULT_CONFIG_PATH);
+                if !path.exists() {
+                    return Ok(Self::default());
+                }
+                path
+            }
+        };
+        let file = File::open(path)
+            .with_context(|| format!("failed to open config {}", path.display()))?;
+        serde_yaml::from_reader(file)
+            .with_context(|| format!("failed to parse config {}", path.display()))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(!self.addresses.is_empty(), "no address to bind");
+        match reqwest::Url::parse(&self.api) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            Ok(_) => bail!("api must be an http(s) URL"),
+            Err(err) => bail!("invalid api URL {:?}: {err}", self.api),
+        }
+        ensure!(self.room_max_users > 0, "room_max_users must be positive");
+        ensure!(
+            !self.auth_timeout.is_zero(),
+            "auth_timeout must be positive"
+        );
+        ensure!(
+            !self.heartbeat_timeout.is_zero(),
+            "heartbeat_timeout must be positive"
+        );
+        ensure!(
+            !self.ready_timeout.is_zero(),
+            "ready_timeout must be positive"
+        );
+        self.log.stdout_filter()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use crate::{Chart, Record};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::warn;
+
+/// One finished game, as stored in the history file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatchRecord {
+    pub room: String,
+    pub chart: Option<Chart>,
+    pub participants: Vec<i32>,
+    pub records: Vec<Record>,
+    pub aborted: Vec<i32>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+}
+
+impl MatchRecord {
+    pub fn involves(&self, user: i32) -> bool {
+        self.participants.contains(&user)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct HistoryQuery {
+    pub user: Option<i32>,
+    pub room: Option<String>,
+}
+
+impl HistoryQuery {
+    pub fn matches(&self, record: &MatchRecord) -> bool {
+        self.user.is_none_or(|it| record.involves(it))
+            && self.room.as_ref().is_none_or(|it| &record.room == it)
+    }
+}
+
+/// Append-only JSON Lines store of finished games.
+pub struct MatchHistory {
+    file: Mutex<File>,
+}
+
+impl MatchHistory {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed to open match history {}", path.display()))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn append(&self, record: &MatchRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Read every match in the history file at `path` that satisfies `query`,
+/// oldest first. Lines that fail to parse are skipped.
+pub fn read_history(path: &Path, query: &HistoryQuery) -> Result<Vec<MatchRecord>> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open match history {}", path.display()))?;
+    let mut res = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<MatchRecord>(&line) {
+            Ok(record) if query.matches(&record) => res.push(record),
+            Ok(_) => {}
+            Err(err) => warn!(
+                "skipping malformed history entry at line {}: {err}",
+                index + 1
+            ),
+        }
+    }
+    Ok(res)
+}
@@ -1,5 +1,19 @@
+mod adapter;
+
+mod auth;
+pub use auth::*;
+
+mod config;
+pub use config::*;
+
+mod history;
+pub use history::*;
+
 mod l10n;
 
+mod provider;
+pub use provider::*;
+
 mod room;
 pub use room::*;
 
@@ -9,15 +23,12 @@ pub use server::*;
 mod session;
 pub use session::*;
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result, bail};
+use clap::{Parser, Subcommand};
 use std::{
-    collections::{
-        HashMap,
-        hash_map::{Entry, VacantEntry},
-    },
-    net::{Ipv6Addr, SocketAddr},
-    path::Path,
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
 };
 use tokio::{net::TcpListener, sync::RwLock};
 use tracing::warn;
@@ -27,30 +38,27 @@ use uuid::Uuid;
 pub type SafeMap<K, V> = RwLock<HashMap<K, V>>;
 pub type IdMap<V> = SafeMap<Uuid, V>;
 
-fn vacant_entry<V>(map: &mut HashMap<Uuid, V>) -> VacantEntry<'_, Uuid, V> {
+fn vacant_id<V>(map: &HashMap<Uuid, V>) -> Uuid {
     let mut id = Uuid::new_v4();
     while map.contains_key(&id) {
         // 修正此处的语法错误
         id = Uuid::new_v4();
     }
-    match map.entry(id) {
-        Entry::Vacant(entry) => entry,
-        _ => unreachable!(),
-    }
+    id
 }
 
-pub fn init_log(file: &str) -> Result<WorkerGuard> {
-    use tracing::{Level, metadata::LevelFilter};
+pub fn init_log(file: &str, config: &LogConfig) -> Result<WorkerGuard> {
+    use tracing::Level;
     use tracing_log::LogTracer;
-    use tracing_subscriber::{EnvFilter, filter, fmt, prelude::*};
+    use tracing_subscriber::{filter, fmt, prelude::*};
 
-    let log_dir = Path::new("log");
+    let log_dir = &config.dir;
     if log_dir.exists() {
         if !log_dir.is_dir() {
-            panic!("log exists and is not a folder");
+            bail!("{} exists and is not a folder", log_dir.display());
         }
     } else {
-        std::fs::create_dir(log_dir).expect("failed to create log folder");
+        std::fs::create_dir_all(log_dir).context("failed to create log folder")?;
     }
 
     LogTracer::init()?;
@@ -62,12 +70,12 @@ pub fn init_log(file: &str) -> Result<WorkerGuard> {
         .with(
             fmt::layer()
                 .with_writer(non_blocking)
-                .with_filter(LevelFilter::DEBUG),
+                .with_filter(config.file_level),
         )
         .with(
             fmt::layer()
                 .with_writer(std::io::stdout)
-                .with_filter(EnvFilter::from_default_env()),
+                .with_filter(config.stdout_filter()?),
         )
         .with(
             filter::Targets::new()
@@ -82,32 +90,115 @@ pub fn init_log(file: &str) -> Result<WorkerGuard> {
 }
 
 /// Command line arguments
+///
+/// Anything given here overrides the config file.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
     #[clap(
         short,
         long,
-        default_value_t = 12346,
+        env = "PHIRA_MP_CONFIG",
+        help = "Path to the config file [default: server_config.yml, if present]"
+    )]
+    config: Option<PathBuf>,
+
+    #[clap(
+        short,
+        long,
+        env = "PHIRA_MP_PORT",
         help = "Specify the port number to use for the server"
     )]
-    port: u16,
+    port: Option<u16>,
+
+    #[clap(
+        long = "bind",
+        env = "PHIRA_MP_BIND",
+        value_delimiter = ',',
+        help = "Addresses to listen on"
+    )]
+    addresses: Option<Vec<IpAddr>>,
+
+    #[clap(long, env = "PHIRA_MP_API", help = "Base URL of the Phira API")]
+    api: Option<String>,
+
+    #[clap(long, env = "PHIRA_MP_LOG_DIR", help = "Folder to write log files to")]
+    log_dir: Option<PathBuf>,
+
+    #[clap(
+        long,
+        env = "PHIRA_MP_LOG",
+        help = "Log filter for stdout, e.g. `info` or `phira_mp_server=debug`"
+    )]
+    log: Option<String>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print finished games from the match history as JSON Lines
+    History {
+        #[clap(long, help = "Only games this user took part in")]
+        user: Option<i32>,
+        #[clap(long, help = "Only games played in this room")]
+        room: Option<String>,
+    },
+}
+
+impl Args {
+    fn apply(self, config: &mut ServerConfig) {
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(addresses) = self.addresses {
+            config.addresses = addresses;
+        }
+        if let Some(api) = self.api {
+            config.api = api;
+        }
+        if let Some(dir) = self.log_dir {
+            config.log.dir = dir;
+        }
+        if let Some(log) = self.log {
+            config.log.stdout = Some(log);
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let _guard = init_log("phira-mp")?;
+    let mut args = Args::parse();
+    let mut config = ServerConfig::load(args.config.as_deref())?;
+    let command = args.command.take();
+    args.apply(&mut config);
+    config.validate().context("invalid config")?;
+
+    if let Some(Command::History { user, room }) = command {
+        let Some(path) = &config.history else {
+            bail!("match history is disabled, set `history` in the config to enable it");
+        };
+        for record in read_history(path, &HistoryQuery { user, room })? {
+            println!("{}", serde_json::to_string(&record)?);
+        }
+        return Ok(());
+    }
+
+    let _guard = init_log("phira-mp", &config.log)?;
 
-    let args = Args::parse();
-    let port = args.port;
-    let addrs: &[SocketAddr] = &[SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port)];
+    let addrs: Vec<_> = config
+        .addresses
+        .iter()
+        .map(|it| SocketAddr::new(*it, config.port))
+        .collect();
 
     // 打印本地地址和端口
-    for addr in addrs {
+    for addr in &addrs {
         println!("Local Address: {}", addr);
     }
 
-    let listener: Server = TcpListener::bind(addrs).await?.into();
+    let listener = Server::new(config, TcpListener::bind(&addrs[..]).await?)?;
 
     loop {
         if let Err(err) = listener.accept().await {
@@ -0,0 +1,172 @@
+use crate::{Chart, Record};
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use phira_mp_common::PlayRecord;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicI32, Ordering},
+};
+use tokio::sync::Mutex;
+
+#[async_trait]
+pub trait ChartProvider: Send + Sync {
+    async fn chart(&self, id: i32) -> Result<Chart>;
+}
+
+#[async_trait]
+pub trait RecordProvider: Send + Sync {
+    /// Look up a record that was uploaded somewhere else, by its id.
+    async fn record(&self, id: i32) -> Result<Record>;
+
+    /// Accept a record sent directly by the player.
+    async fn submit(&self, player: i32, record: PlayRecord) -> Result<Record> {
+        let _ = (player, record);
+        bail!("record submission is not supported");
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ChartConfig {
+    #[default]
+    Phira,
+    Local {
+        path: PathBuf,
+    },
+}
+
+impl ChartConfig {
+    pub fn build(&self, api: &str) -> Result<Box<dyn ChartProvider>> {
+        Ok(match self {
+            Self::Phira => Box::new(PhiraProvider::new(api)),
+            Self::Local { path } => Box::new(LocalChartProvider::load(path)?),
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum RecordConfig {
+    #[default]
+    Phira,
+    Local,
+}
+
+impl RecordConfig {
+    pub fn build(&self, api: &str) -> Result<Box<dyn RecordProvider>> {
+        Ok(match self {
+            Self::Phira => Box::new(PhiraProvider::new(api)),
+            Self::Local => Box::new(LocalRecordProvider::default()),
+        })
+    }
+}
+
+/// Fetches charts and records from the Phira API.
+pub struct PhiraProvider {
+    client: reqwest::Client,
+    host: String,
+}
+
+impl PhiraProvider {
+    pub fn new(host: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            host: host.trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+#[async_trait]
+impl ChartProvider for PhiraProvider {
+    async fn chart(&self, id: i32) -> Result<Chart> {
+        Ok(self
+            .client
+            .get(format!("{}/chart/{id}", self.host))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl RecordProvider for PhiraProvider {
+    async fn record(&self, id: i32) -> Result<Record> {
+        Ok(self
+            .client
+            .get(format!("{}/record/{id}", self.host))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+}
+
+/// Serves charts from a YAML catalog, a list of `{ id, name }`.
+pub struct LocalChartProvider {
+    charts: HashMap<i32, Chart>,
+}
+
+impl LocalChartProvider {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open chart catalog {}", path.display()))?;
+        let charts: Vec<Chart> = serde_yaml::from_reader(file)
+            .with_context(|| format!("failed to parse chart catalog {}", path.display()))?;
+        Ok(Self {
+            charts: charts.into_iter().map(|it| (it.id, it)).collect(),
+        })
+    }
+}
+
+#[async_trait]
+impl ChartProvider for LocalChartProvider {
+    async fn chart(&self, id: i32) -> Result<Chart> {
+        match self.charts.get(&id) {
+            Some(chart) => Ok(chart.clone()),
+            None => bail!("chart not found"),
+        }
+    }
+}
+
+/// Trusts whatever the players submit and keeps it in memory.
+#[derive(Default)]
+pub struct LocalRecordProvider {
+    next_id: AtomicI32,
+    records: Mutex<HashMap<i32, Record>>,
+}
+
+#[async_trait]
+impl RecordProvider for LocalRecordProvider {
+    async fn record(&self, id: i32) -> Result<Record> {
+        match self.records.lock().await.get(&id) {
+            Some(record) => Ok(record.clone()),
+            None => bail!("record not found"),
+        }
+    }
+
+    async fn submit(&self, player: i32, record: PlayRecord) -> Result<Record> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let record = Record {
+            id,
+            player,
+            score: record.score,
+            perfect: record.perfect,
+            good: record.good,
+            bad: record.bad,
+            miss: record.miss,
+            max_combo: record.max_combo,
+            accuracy: record.accuracy,
+            full_combo: record.full_combo,
+            std: record.std,
+            std_score: record.std_score,
+        };
+        self.records.lock().await.insert(id, record.clone());
+        Ok(record)
+    }
+}
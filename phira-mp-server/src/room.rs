@@ -1,19 +1,27 @@
-use crate::{Chart, Record, User};
+use crate::{Chart, MatchRecord, Record, ServerState, User, l10n::LANGUAGE, tl};
 use anyhow::{Result, bail};
-use phira_mp_common::{ClientRoomState, Message, RoomId, RoomState, ServerCommand};
+use chrono::{DateTime, Utc};
+use phira_mp_common::{
+    ClientRoomState, Message, PlayerResult, RankingMetric, ReadyTimeoutAction, RoomId, RoomInfo,
+    RoomState, SYSTEM_USER, ServerCommand,
+};
 use rand::seq::IndexedRandom;
 use std::{
     collections::{HashMap, HashSet},
-    ops::Deref,
+    ops::{Deref, DerefMut},
     sync::{
-        Arc, Weak,
+        Arc, Mutex, Weak,
         atomic::{AtomicBool, Ordering},
     },
+    time::Duration,
 };
-use tokio::sync::RwLock;
-use tracing::{debug, info};
-
-const ROOM_MAX_USERS: usize = 8;
+use subtle::ConstantTimeEq;
+use tokio::{
+    sync::RwLock,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::{debug, error, info, warn};
 
 #[derive(Default, Debug)]
 pub enum InternalRoomState {
@@ -21,10 +29,13 @@ pub enum InternalRoomState {
     SelectChart,
     WaitForReady {
         started: HashSet<i32>,
+        deadline: Instant,
     },
     Playing {
         results: HashMap<i32, Record>,
         aborted: HashSet<i32>,
+        participants: Vec<i32>,
+        started_at: DateTime<Utc>,
     },
 }
 
@@ -38,35 +49,100 @@ impl InternalRoomState {
     }
 }
 
+/// Rank players best first. Tied players share a rank; score breaks ties in
+/// the ordering only.
+pub fn rank_results(metric: RankingMetric, results: &HashMap<i32, Record>) -> Vec<PlayerResult> {
+    let key = |it: &Record| match metric {
+        RankingMetric::Score => it.score as f32,
+        RankingMetric::Accuracy => it.accuracy,
+        RankingMetric::MaxCombo => it.max_combo as f32,
+        RankingMetric::Std => -it.std,
+    };
+    let mut records: Vec<_> = results.values().collect();
+    records.sort_by(|a, b| {
+        key(b)
+            .total_cmp(&key(a))
+            .then_with(|| b.score.cmp(&a.score))
+    });
+    let mut ranking: Vec<PlayerResult> = Vec::with_capacity(records.len());
+    for (i, record) in records.iter().enumerate() {
+        let rank = match ranking.last() {
+            Some(last) if key(records[i - 1]) == key(record) => last.rank,
+            _ => i as u32 + 1,
+        };
+        ranking.push(PlayerResult {
+            user: record.player,
+            rank,
+            score: record.score,
+            accuracy: record.accuracy,
+            full_combo: record.full_combo,
+            max_combo: record.max_combo,
+            perfect: record.perfect,
+            good: record.good,
+            bad: record.bad,
+            miss: record.miss,
+            std: record.std,
+        });
+    }
+    ranking
+}
+
 pub struct Room {
     pub id: RoomId,
+    /// Weak, the server holds the room.
+    server: Weak<ServerState>,
+    this: Weak<Room>,
     pub host: RwLock<Weak<User>>,
     pub state: RwLock<InternalRoomState>,
 
     pub live: AtomicBool,
     pub locked: AtomicBool,
     pub cycle: AtomicBool,
+    pub private: AtomicBool,
 
+    password: RwLock<Option<String>>,
+    banned: RwLock<HashSet<i32>>,
+    ranking_metric: RwLock<RankingMetric>,
+    ready_timeout_action: RwLock<ReadyTimeoutAction>,
     users: RwLock<Vec<Weak<User>>>,
     monitors: RwLock<Vec<Weak<User>>>,
     pub chart: RwLock<Option<Chart>>,
+    watchdog: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl Room {
-    pub fn new(id: RoomId, host: Weak<User>) -> Self {
-        Self {
+    pub fn new(
+        id: RoomId,
+        host: Weak<User>,
+        server: &Arc<ServerState>,
+        password: Option<String>,
+        private: bool,
+    ) -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
             id,
+            server: Arc::downgrade(server),
+            this: Weak::clone(this),
             host: host.clone().into(),
             state: RwLock::default(),
 
             live: AtomicBool::new(false),
             locked: AtomicBool::new(false),
             cycle: AtomicBool::new(false),
+            private: AtomicBool::new(private),
 
+            password: password.into(),
+            banned: RwLock::default(),
+            ranking_metric: RwLock::default(),
+            ready_timeout_action: RwLock::default(),
             users: vec![host].into(),
             monitors: Vec::new().into(),
             chart: RwLock::default(),
-        }
+            watchdog: Mutex::default(),
+        })
+    }
+
+    fn server(&self) -> Arc<ServerState> {
+        self.server.upgrade().expect("server dropped")
     }
 
     pub fn is_live(&self) -> bool {
@@ -81,6 +157,10 @@ impl Room {
         self.cycle.load(Ordering::SeqCst)
     }
 
+    pub fn is_private(&self) -> bool {
+        self.private.load(Ordering::SeqCst)
+    }
+
     pub async fn client_room_state(&self) -> RoomState {
         self.state
             .read()
@@ -96,7 +176,7 @@ impl Room {
             locked: self.is_locked(),
             cycle: self.is_cycle(),
             is_host: self.check_host(user).await.is_ok(),
-            is_ready: matches!(&*self.state.read().await, InternalRoomState::WaitForReady { started } if started.contains(&user.id)),
+            is_ready: matches!(&*self.state.read().await, InternalRoomState::WaitForReady { started, .. } if started.contains(&user.id)),
             users: self
                 .users
                 .read()
@@ -105,6 +185,79 @@ impl Room {
                 .chain(self.monitors.read().await.iter())
                 .filter_map(|it| it.upgrade().map(|it| (it.id, it.to_info())))
                 .collect(),
+            ready_countdown: match &*self.state.read().await {
+                InternalRoomState::WaitForReady { deadline, .. } => Some(
+                    deadline
+                        .saturating_duration_since(Instant::now())
+                        .as_secs_f32(),
+                ),
+                _ => None,
+            },
+        }
+    }
+
+    pub async fn has_password(&self) -> bool {
+        self.password.read().await.is_some()
+    }
+
+    pub async fn check_password(&self, password: Option<&str>) -> bool {
+        match self.password.read().await.as_deref() {
+            // don't let response times tell how much of a guess was right
+            Some(expected) => {
+                password.is_some_and(|it| it.as_bytes().ct_eq(expected.as_bytes()).into())
+            }
+            None => true,
+        }
+    }
+
+    pub async fn set_password(&self, password: Option<String>) {
+        *self.password.write().await = password;
+    }
+
+    pub async fn is_banned(&self, user: i32) -> bool {
+        self.banned.read().await.contains(&user)
+    }
+
+    pub async fn ban(&self, user: i32) {
+        self.banned.write().await.insert(user);
+    }
+
+    pub async fn ranking_metric(&self) -> RankingMetric {
+        *self.ranking_metric.read().await
+    }
+
+    pub async fn set_ranking_metric(&self, metric: RankingMetric) {
+        *self.ranking_metric.write().await = metric;
+        self.send(Message::RankingMetric { metric }).await;
+    }
+
+    pub async fn ready_timeout_action(&self) -> ReadyTimeoutAction {
+        *self.ready_timeout_action.read().await
+    }
+
+    pub async fn set_ready_timeout_action(&self, action: ReadyTimeoutAction) {
+        *self.ready_timeout_action.write().await = action;
+        self.send(Message::ReadyTimeoutAction { action }).await;
+    }
+
+    pub async fn info(&self) -> RoomInfo {
+        RoomInfo {
+            id: self.id.clone(),
+            host: self
+                .host
+                .read()
+                .await
+                .upgrade()
+                .map(|it| it.name.clone())
+                .unwrap_or_default(),
+            users: self.users().await.len() as u32,
+            max_users: self.server().config.room_max_users as u32,
+            state: self.client_room_state().await,
+            locked: self.is_locked(),
+            cycle: self.is_cycle(),
+            live: self.is_live(),
+            password: self.has_password().await,
+            chart: self.chart.read().await.as_ref().map(|it| it.name.clone()),
         }
     }
 
@@ -122,7 +275,7 @@ impl Room {
         } else {
             let mut guard = self.users.write().await;
             guard.retain(|it| it.strong_count() > 0);
-            if guard.len() >= ROOM_MAX_USERS {
+            if guard.len() >= self.server().config.room_max_users {
                 false
             } else {
                 guard.push(user);
@@ -149,6 +302,14 @@ impl Room {
             .collect()
     }
 
+    pub async fn find_user(&self, id: i32) -> Option<Arc<User>> {
+        self.users()
+            .await
+            .into_iter()
+            .chain(self.monitors().await)
+            .find(|it| it.id == id)
+    }
+
     pub async fn check_host(&self, user: &User) -> Result<()> {
         if self.host.read().await.upgrade().map(|it| it.id) != Some(user.id) {
             bail!("only host can do this");
@@ -161,6 +322,7 @@ impl Room {
         self.broadcast(ServerCommand::Message(msg)).await;
     }
 
+    #[allow(clippy::useless_conversion)]
     pub async fn broadcast(&self, cmd: ServerCommand) {
         debug!("broadcast {cmd:?}");
         for session in self
@@ -188,6 +350,19 @@ impl Room {
         .await;
     }
 
+    /// Send a chat line from [`SYSTEM_USER`], rendered in each recipient's
+    /// language.
+    pub async fn send_system(&self, content: impl Fn() -> String) {
+        for user in self.users().await.into_iter().chain(self.monitors().await) {
+            let content = LANGUAGE.sync_scope(Arc::new(user.lang.clone()), &content);
+            user.try_send(ServerCommand::Message(Message::Chat {
+                user: SYSTEM_USER,
+                content,
+            }))
+            .await;
+        }
+    }
+
     /// Return: should the room be dropped
     #[must_use]
     pub async fn on_user_leave(&self, user: &User) -> bool {
@@ -196,6 +371,12 @@ impl Room {
             name: user.name.clone(),
         })
         .await;
+        self.remove_user(user).await
+    }
+
+    /// [`Room::on_user_leave`] without announcing it.
+    #[must_use]
+    async fn remove_user(&self, user: &User) -> bool {
         *user.room.write().await = None;
         (if user.monitor.load(Ordering::SeqCst) {
             &self.monitors
@@ -223,6 +404,259 @@ impl Room {
         false
     }
 
+    pub async fn on_played(&self, user: &User, record: Record) -> Result<()> {
+        debug!(
+            room = self.id.to_string(),
+            user = user.id,
+            "user played: {record:?}"
+        );
+        self.send(Message::Played {
+            user: user.id,
+            score: record.score,
+            accuracy: record.accuracy,
+            full_combo: record.full_combo,
+        })
+        .await;
+        let mut guard = self.state.write().await;
+        if let InternalRoomState::Playing {
+            results,
+            aborted,
+            participants,
+            ..
+        } = guard.deref_mut()
+        {
+            if !participants.contains(&user.id) {
+                bail!("not participating");
+            }
+            if aborted.contains(&user.id) {
+                bail!("aborted");
+            }
+            if results.insert(user.id, record).is_some() {
+                bail!("already uploaded");
+            }
+            drop(guard);
+            self.check_all_ready().await;
+        }
+        Ok(())
+    }
+
+    pub async fn abort(&self, user: &User) -> Result<()> {
+        let mut guard = self.state.write().await;
+        if let InternalRoomState::Playing {
+            results, aborted, ..
+        } = guard.deref_mut()
+        {
+            if results.contains_key(&user.id) {
+                bail!("already uploaded");
+            }
+            if !aborted.insert(user.id) {
+                bail!("aborted");
+            }
+            drop(guard);
+            self.send(Message::Abort { user: user.id }).await;
+            self.check_all_ready().await;
+        }
+        Ok(())
+    }
+
+    /// Remove `target` on the host's behalf, optionally banning them for the
+    /// rest of the room's lifetime.
+    pub async fn kick(&self, target: &User, ban: bool) {
+        if ban {
+            self.ban(target.id).await;
+        }
+        let (user, name) = (target.id, target.name.clone());
+        // stands in for `LeaveRoom`, the target still gets it too
+        self.send(if ban {
+            Message::BanUser { user, name }
+        } else {
+            Message::KickUser { user, name }
+        })
+        .await;
+        // the host is still here, so the room never becomes empty
+        let _ = self.remove_user(target).await;
+    }
+
+    pub async fn change_host(&self, new_host: &Arc<User>) {
+        let old = std::mem::replace(&mut *self.host.write().await, Arc::downgrade(new_host));
+        self.send(Message::NewHost { user: new_host.id }).await;
+        if let Some(old) = old.upgrade() {
+            old.try_send(ServerCommand::ChangeHost(false)).await;
+        }
+        new_host.try_send(ServerCommand::ChangeHost(true)).await;
+    }
+
+    fn save_history(&self, entry: MatchRecord) {
+        let Some(history) = self.server().history.clone() else {
+            return;
+        };
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = history.append(&entry) {
+                error!("failed to save match history: {err:?}");
+            }
+        });
+    }
+
+    fn play_time(&self, chart: Option<&Chart>) -> Duration {
+        let server = self.server();
+        let length = chart
+            .and_then(|it| it.duration)
+            .filter(|it| *it > 0.)
+            .and_then(|it| Duration::try_from_secs_f32(it).ok())
+            .unwrap_or(server.config.default_chart_length);
+        length.saturating_add(server.config.play_grace)
+    }
+
+    /// Call [`Room::on_timeout`] after `after`, replacing any pending timeout.
+    pub fn start_watchdog(&self, after: Duration) {
+        let room = Weak::clone(&self.this);
+        let handle = tokio::spawn(async move {
+            time::sleep(after).await;
+            if let Some(room) = room.upgrade() {
+                room.on_timeout().await;
+            }
+        });
+        if let Some(old) = self.watchdog.lock().unwrap().replace(handle) {
+            old.abort();
+        }
+    }
+
+    pub fn stop_watchdog(&self) {
+        if let Some(handle) = self.watchdog.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+
+    async fn on_timeout(&self) {
+        // detach ourselves first, changing the state would abort this very task
+        self.watchdog.lock().unwrap().take();
+        let guard = self.state.read().await;
+        match guard.deref() {
+            InternalRoomState::WaitForReady { .. } => {
+                drop(guard);
+                self.on_ready_timeout().await;
+            }
+            InternalRoomState::Playing { .. } => {
+                drop(guard);
+                self.on_play_timeout().await;
+            }
+            InternalRoomState::SelectChart => {}
+        }
+    }
+
+    async fn on_ready_timeout(&self) {
+        let action = self.ready_timeout_action().await;
+        info!(room = self.id.to_string(), "ready timeout, {action:?}");
+        match action {
+            ReadyTimeoutAction::Cancel => {
+                {
+                    // someone may have got ready since, starting the game
+                    let mut guard = self.state.write().await;
+                    if !matches!(*guard, InternalRoomState::WaitForReady { .. }) {
+                        return;
+                    }
+                    *guard = InternalRoomState::SelectChart;
+                }
+                self.send_system(|| tl!("ready-timeout-cancel").into_owned())
+                    .await;
+                self.send(Message::CancelGame { user: SYSTEM_USER }).await;
+                self.on_state_change().await;
+            }
+            ReadyTimeoutAction::StartWithout => {
+                if self.start_game().await {
+                    // nobody might be left to play
+                    self.check_all_ready().await;
+                }
+            }
+        }
+    }
+
+    /// Abort everyone who hasn't finished yet, so that the game can end.
+    async fn on_play_timeout(&self) {
+        let mut guard = self.state.write().await;
+        let InternalRoomState::Playing {
+            results,
+            aborted,
+            participants,
+            ..
+        } = guard.deref_mut()
+        else {
+            return;
+        };
+        let missing: Vec<_> = self
+            .users()
+            .await
+            .into_iter()
+            .filter(|it| {
+                participants.contains(&it.id)
+                    && !results.contains_key(&it.id)
+                    && !aborted.contains(&it.id)
+            })
+            .collect();
+        aborted.extend(missing.iter().map(|it| it.id));
+        drop(guard);
+        for user in missing {
+            warn!(
+                room = self.id.to_string(),
+                user = user.id,
+                "play time exceeded, aborting"
+            );
+            self.send(Message::Abort { user: user.id }).await;
+            self.send_system(|| tl!("play-timeout", "name" => user.name.clone()))
+                .await;
+        }
+        self.check_all_ready().await;
+    }
+
+    /// Start playing with the users who are ready, the others sitting out,
+    /// which only happens on [`ReadyTimeoutAction::StartWithout`]. Does
+    /// nothing if the room isn't waiting for ready anymore, e.g. because the
+    /// game was cancelled or started in the meantime.
+    async fn start_game(&self) -> bool {
+        let participants = {
+            let mut guard = self.state.write().await;
+            let InternalRoomState::WaitForReady { started, .. } = guard.deref() else {
+                return false;
+            };
+            let participants: Vec<_> = self
+                .users()
+                .await
+                .iter()
+                .map(|it| it.id)
+                .filter(|it| started.contains(it))
+                .collect();
+            *guard = InternalRoomState::Playing {
+                results: HashMap::new(),
+                aborted: HashSet::new(),
+                participants: participants.clone(),
+                started_at: Utc::now(),
+            };
+            participants
+        };
+        info!(room = self.id.to_string(), "game start");
+        for user in self.users().await {
+            if participants.contains(&user.id) {
+                continue;
+            }
+            self.send(Message::SitOut { user: user.id }).await;
+            self.send_system(|| tl!("ready-timeout-sit-out", "name" => user.name.clone()))
+                .await;
+        }
+        let server = self.server();
+        let lead = server.config.start_lead;
+        self.send(Message::StartAt {
+            time: server.clock() + lead.as_micros() as u64,
+        })
+        .await;
+        // still sent at once: clients that don't know `StartAt` start on it,
+        // as they always did
+        self.send(Message::StartPlaying).await;
+        self.reset_game_time().await;
+        self.start_watchdog(lead.saturating_add(self.play_time(self.chart.read().await.as_ref())));
+        self.on_state_change().await;
+        true
+    }
+
     pub async fn reset_game_time(&self) {
         for user in self.users().await {
             user.game_time
@@ -230,10 +664,11 @@ impl Room {
         }
     }
 
+    #[allow(clippy::collapsible_match, clippy::useless_conversion)]
     pub async fn check_all_ready(&self) {
         let guard = self.state.read().await;
         match guard.deref() {
-            InternalRoomState::WaitForReady { started } => {
+            InternalRoomState::WaitForReady { started, .. } => {
                 if self
                     .users()
                     .await
@@ -242,29 +677,53 @@ impl Room {
                     .all(|it| started.contains(&it.id))
                 {
                     drop(guard);
-                    info!(room = self.id.to_string(), "game start");
-                    self.send(Message::StartPlaying).await;
-                    self.reset_game_time().await;
-                    *self.state.write().await = InternalRoomState::Playing {
-                        results: HashMap::new(),
-                        aborted: HashSet::new(),
-                    };
-                    self.on_state_change().await;
+                    self.start_game().await;
                 }
             }
-            InternalRoomState::Playing { results, aborted } => {
+            InternalRoomState::Playing {
+                results,
+                aborted,
+                participants,
+                started_at,
+            } => {
                 if self
                     .users()
                     .await
                     .into_iter()
+                    .filter(|it| participants.contains(&it.id))
                     .all(|it| results.contains_key(&it.id) || aborted.contains(&it.id))
                 {
+                    let metric = self.ranking_metric().await;
+                    let ranking = rank_results(metric, results);
+                    let mut aborted: Vec<_> = aborted.iter().copied().collect();
+                    aborted.sort_unstable();
+                    let entry = MatchRecord {
+                        room: self.id.to_string(),
+                        chart: self.chart.read().await.clone(),
+                        participants: participants.clone(),
+                        records: ranking
+                            .iter()
+                            .filter_map(|it| results.get(&it.user).cloned())
+                            .collect(),
+                        aborted: aborted.clone(),
+                        started_at: *started_at,
+                        ended_at: Utc::now(),
+                    };
                     drop(guard);
-                    // TODO print results
+                    self.stop_watchdog();
+                    self.save_history(entry);
+                    info!(
+                        room = self.id.to_string(),
+                        "game end, ranking by {metric:?}: {ranking:?}, aborted: {aborted:?}"
+                    );
+                    self.send(Message::GameResults {
+                        metric,
+                        ranking,
+                        aborted,
+                    })
+                    .await;
                     self.send(Message::GameEnd).await;
-                    // dbg!(2);
                     *self.state.write().await = InternalRoomState::SelectChart;
-                    // dbg!(3);
                     if self.is_cycle() {
                         debug!(room = self.id.to_string(), "cycling");
                         let host = Weak::clone(&*self.host.read().await);
@@ -277,12 +736,7 @@ impl Room {
                                 .unwrap_or_default();
                             users.into_iter().nth(index).unwrap()
                         };
-                        *self.host.write().await = Arc::downgrade(&new_host);
-                        self.send(Message::NewHost { user: new_host.id }).await;
-                        if let Some(old) = host.upgrade() {
-                            old.try_send(ServerCommand::ChangeHost(false)).await;
-                        }
-                        new_host.try_send(ServerCommand::ChangeHost(true)).await;
+                        self.change_host(&new_host).await;
                     }
                     self.on_state_change().await;
                 }
@@ -291,3 +745,57 @@ impl Room {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(player: i32, score: i32, accuracy: f32, std: f32) -> Record {
+        Record {
+            id: player,
+            player,
+            score,
+            perfect: 0,
+            good: 0,
+            bad: 0,
+            miss: 0,
+            max_combo: 0,
+            accuracy,
+            full_combo: false,
+            std,
+            std_score: 0.,
+        }
+    }
+
+    fn ranks(metric: RankingMetric, records: &[Record]) -> Vec<(i32, u32)> {
+        let results = records.iter().map(|it| (it.player, it.clone())).collect();
+        rank_results(metric, &results)
+            .into_iter()
+            .map(|it| (it.user, it.rank))
+            .collect()
+    }
+
+    #[test]
+    fn ties_share_a_rank() {
+        let records = [
+            record(1, 900_000, 0.98, 20.),
+            record(2, 950_000, 0.98, 10.),
+            record(3, 990_000, 0.95, 15.),
+            record(4, 800_000, 0.90, 15.),
+        ];
+        // score orders the tie, the rank after it is skipped
+        assert_eq!(
+            ranks(RankingMetric::Accuracy, &records),
+            [(2, 1), (1, 1), (3, 3), (4, 4)]
+        );
+        // lower is better
+        assert_eq!(
+            ranks(RankingMetric::Std, &records),
+            [(2, 1), (3, 2), (4, 2), (1, 4)]
+        );
+        assert_eq!(
+            ranks(RankingMetric::Score, &records),
+            [(3, 1), (2, 2), (1, 3), (4, 4)]
+        );
+    }
+}
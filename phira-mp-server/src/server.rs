@@ -1,29 +1,24 @@
-use crate::{IdMap, Room, SafeMap, Session, User, vacant_entry};
+use crate::{
+    Authenticator, ChartProvider, IdMap, MatchHistory, RecordProvider, Room, SafeMap, ServerConfig,
+    Session, User, vacant_id,
+};
 use anyhow::Result;
 use phira_mp_common::RoomId;
-use serde::Deserialize;
-use std::{fs::File, sync::Arc};
-use tokio::{net::TcpListener, sync::mpsc, task::JoinHandle};
-use tracing::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Instant};
+use tokio::{net::TcpListener, sync::mpsc, task::JoinHandle, time};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chart {
     pub id: i32,
     pub name: String,
+    /// Length in seconds, if known.
+    pub duration: Option<f32>,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct ServerConfig {
-    pub monitors: Vec<i32>,
-}
-impl Default for ServerConfig {
-    fn default() -> Self {
-        Self { monitors: vec![2] }
-    }
-}
-
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Record {
     pub id: i32,
     pub player: i32,
@@ -41,12 +36,25 @@ pub struct Record {
 
 pub struct ServerState {
     pub config: ServerConfig,
+    pub authenticator: Box<dyn Authenticator>,
+    pub charts: Box<dyn ChartProvider>,
+    pub records: Box<dyn RecordProvider>,
+    pub history: Option<Arc<MatchHistory>>,
     pub sessions: IdMap<Arc<Session>>,
     pub users: SafeMap<i32, Arc<User>>,
 
     pub rooms: SafeMap<RoomId, Arc<Room>>,
 
     pub lost_con_tx: mpsc::Sender<Uuid>,
+    epoch: Instant,
+}
+
+impl ServerState {
+    /// Monotonic server clock in microseconds, as sent in
+    /// [`ServerCommand::Pong`](phira_mp_common::ServerCommand::Pong).
+    pub fn clock(&self) -> u64 {
+        self.epoch.elapsed().as_micros() as u64
+    }
 }
 
 pub struct Server {
@@ -55,21 +63,29 @@ pub struct Server {
     lost_con_handle: JoinHandle<()>,
 }
 
-impl From<TcpListener> for Server {
-    fn from(listener: TcpListener) -> Self {
+impl Server {
+    pub fn new(config: ServerConfig, listener: TcpListener) -> Result<Self> {
         let (lost_con_tx, mut lost_con_rx) = mpsc::channel(16);
-        let config: ServerConfig = File::open("server_config.yml")
-            .ok()
-            .and_then(|f| serde_yaml::from_reader(f).ok())
-            .unwrap_or_default();
+        let authenticator = config.auth.build(&config.api)?;
+        let charts = config.charts.build(&config.api)?;
+        let records = config.records.build(&config.api)?;
+        let history = match &config.history {
+            Some(path) => Some(Arc::new(MatchHistory::open(path)?)),
+            None => None,
+        };
         let state = Arc::new(ServerState {
             config,
+            authenticator,
+            charts,
+            records,
+            history,
             sessions: IdMap::default(),
             users: SafeMap::default(),
 
             rooms: SafeMap::default(),
 
             lost_con_tx,
+            epoch: Instant::now(),
         });
         let lost_con_handle = tokio::spawn({
             let state = Arc::clone(&state);
@@ -91,27 +107,49 @@ impl From<TcpListener> for Server {
             }
         });
 
-        Self {
+        Ok(Self {
             listener,
             state,
 
             lost_con_handle,
-        }
+        })
     }
-}
 
-impl Server {
     pub async fn accept(&self) -> Result<()> {
         let (stream, addr) = self.listener.accept().await?;
-        let mut guard = self.state.sessions.write().await;
-        let entry = vacant_entry(&mut guard);
-        let session = Session::new(*entry.key(), stream, Arc::clone(&self.state)).await?;
-        info!(
-            "received connections from {addr} ({}), version: {}",
-            session.id,
-            session.version()
-        );
-        entry.insert(session);
+        let state = Arc::clone(&self.state);
+        tokio::spawn(async move {
+            let id = vacant_id(&*state.sessions.read().await);
+            let timeout = state.config.auth_timeout;
+            let session =
+                match time::timeout(timeout, Session::new(id, stream, Arc::clone(&state))).await {
+                    Ok(Ok(session)) => session,
+                    Ok(Err(err)) => {
+                        warn!("failed to establish session with {addr}: {err:?}");
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("{addr} did not authenticate in {timeout:?}, dropping");
+                        return;
+                    }
+                };
+            info!(
+                "received connections from {addr} ({}), protocol: {:?}",
+                session.id,
+                session.protocol()
+            );
+            state
+                .sessions
+                .write()
+                .await
+                .insert(id, Arc::clone(&session));
+            // reported before it was registered, so the report found nothing
+            if session.is_lost()
+                && let Err(err) = state.lost_con_tx.send(id).await
+            {
+                error!("failed to mark lost connection ({id}): {err:?}");
+            }
+        });
         Ok(())
     }
 }
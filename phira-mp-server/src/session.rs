@@ -1,22 +1,22 @@
 use crate::{
-    Chart, InternalRoomState, Record, Room, ServerState,
+    InternalRoomState, Room, ServerState, adapter,
     l10n::{LANGUAGE, Language},
     tl,
 };
 use anyhow::{Result, anyhow, bail};
 use phira_mp_common::{
-    ClientCommand, HEARTBEAT_DISCONNECT_TIMEOUT, JoinRoomResponse, Message, ServerCommand, Stream,
-    UserInfo,
+    Capabilities, ClientCommand, Handshake, Hello, JoinRoomResponse, LEGACY_VERSION, Message,
+    PROTOCOL_VERSION, Protocol, ROOM_LIST_PAGE_SIZE, RoomList, SYSTEM_USER, ServerCommand, Stream,
+    UserInfo, Varchar,
 };
-use serde::Deserialize;
 use std::{
     collections::{HashSet, hash_map::Entry},
     ops::DerefMut,
     sync::{
-        Arc, Weak,
+        Arc, OnceLock, Weak,
         atomic::{AtomicBool, AtomicU32, Ordering},
     },
-    time::{Duration, Instant},
+    time::Instant,
 };
 use tokio::{
     net::TcpStream,
@@ -27,8 +27,6 @@ use tokio::{
 use tracing::{Instrument, debug, debug_span, error, info, trace, warn};
 use uuid::Uuid;
 
-const HOST: &str = "https://phira.5wyxi.com";
-
 pub struct User {
     pub id: i32,
     pub name: String,
@@ -92,27 +90,24 @@ impl User {
         let guard = self.room.read().await;
         let room = guard.as_ref().map(Arc::clone);
         drop(guard);
-        if let Some(room) = room {
-            let guard = room.state.read().await;
-            if matches!(*guard, InternalRoomState::Playing { .. }) {
-                warn!(user = self.id, "lost connection on playing, aborting");
-                self.server.users.write().await.remove(&self.id);
-                drop(guard);
-                if room.on_user_leave(&self).await {
-                    self.server.rooms.write().await.remove(&room.id);
-                }
-                return;
-            }
+        let mut timeout = self.server.config.dangle_timeout;
+        if let Some(room) = room
+            && matches!(*room.state.read().await, InternalRoomState::Playing { .. })
+        {
+            warn!(user = self.id, "lost connection on playing");
+            timeout = self.server.config.reconnect_grace;
         }
         let dangle_mark = Arc::new(());
         *self.dangle_mark.lock().await = Some(Arc::clone(&dangle_mark));
         tokio::spawn(async move {
-            time::sleep(Duration::from_secs(10)).await;
+            time::sleep(timeout).await;
             if Arc::strong_count(&dangle_mark) > 1 {
                 let guard = self.room.read().await;
                 let room = guard.as_ref().map(Arc::clone);
                 drop(guard);
                 if let Some(room) = room {
+                    // a game may be on, whether or not it was when we lost them
+                    let _ = room.abort(&self).await;
                     self.server.users.write().await.remove(&self.id);
                     if room.on_user_leave(&self).await {
                         self.server.rooms.write().await.remove(&room.id);
@@ -128,6 +123,7 @@ pub struct Session {
     pub stream: Stream<ServerCommand, ClientCommand>,
     pub user: Arc<User>,
 
+    lost: Arc<AtomicBool>,
     monitor_task_handle: JoinHandle<()>,
 }
 
@@ -138,9 +134,23 @@ impl Session {
         let this_inited = Arc::new(Notify::new());
         let (tx, rx) = oneshot::channel::<Arc<User>>();
         let last_recv: Arc<Mutex<Instant>> = Arc::new(Mutex::new(Instant::now()));
-        let stream = Stream::<ServerCommand, ClientCommand>::new(
-            None,
+        let lost = Arc::new(AtomicBool::new(false));
+        // set before the first packet is handled
+        let protocol = Arc::new(OnceLock::<Protocol>::new());
+        let stream = Stream::<ServerCommand, ClientCommand>::with_codec(
+            Handshake::Accept(Hello {
+                min_version: LEGACY_VERSION,
+                max_version: PROTOCOL_VERSION,
+                capabilities: Capabilities::all(),
+            }),
             stream,
+            {
+                let protocol = Arc::clone(&protocol);
+                move |it| {
+                    let _ = protocol.set(*it);
+                    adapter::codec(it)
+                }
+            },
             Box::new({
                 let this = Arc::clone(&this);
                 let this_inited = Arc::clone(&this_inited);
@@ -148,7 +158,8 @@ impl Session {
                 let server = Arc::clone(&server);
                 let last_recv = Arc::clone(&last_recv);
                 let waiting_for_authenticate = Arc::new(AtomicBool::new(true));
-                let panicked = Arc::new(AtomicBool::new(false));
+                let lost = Arc::clone(&lost);
+                let protocol = Arc::clone(&protocol);
                 move |send_tx, cmd| {
                     let this = Arc::clone(&this);
                     let this_inited = Arc::clone(&this_inited);
@@ -156,14 +167,49 @@ impl Session {
                     let server = Arc::clone(&server);
                     let last_recv = Arc::clone(&last_recv);
                     let waiting_for_authenticate = Arc::clone(&waiting_for_authenticate);
-                    let panicked = Arc::clone(&panicked);
+                    let lost = Arc::clone(&lost);
+                    let protocol = *protocol.get().unwrap();
                     async move {
                         *last_recv.lock().await = Instant::now();
-                        if panicked.load(Ordering::SeqCst) {
+                        if lost.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        let (request, cmd) = match cmd {
+                            ClientCommand::Request { id, command }
+                                if protocol.supports(Capabilities::REQUEST_IDS) =>
+                            {
+                                (Some(id), *command)
+                            }
+                            ClientCommand::Request { .. } => {
+                                warn!("session {id}: request ids weren't negotiated, ignoring");
+                                return;
+                            }
+                            cmd => (None, cmd),
+                        };
+                        let reply = move |resp| match request {
+                            Some(id) => ServerCommand::Response {
+                                id,
+                                response: Box::new(resp),
+                            },
+                            None => resp,
+                        };
+                        if matches!(cmd, ClientCommand::Request { .. }) {
+                            warn!("session {id}: nested request, rejecting");
+                            let _ = send_tx
+                                .send(reply(ServerCommand::Error("nested request".to_owned())))
+                                .await;
                             return;
                         }
                         if matches!(cmd, ClientCommand::Ping) {
-                            let _ = send_tx.send(ServerCommand::Pong).await;
+                            let _ = send_tx
+                                .send(reply(ServerCommand::Pong {
+                                    time: if protocol.supports(Capabilities::CLOCK_SYNC) {
+                                        server.clock()
+                                    } else {
+                                        0
+                                    },
+                                }))
+                                .await;
                             return;
                         }
                         if waiting_for_authenticate.load(Ordering::SeqCst) {
@@ -178,26 +224,7 @@ impl Session {
                                             bail!("invalid token");
                                         }
                                         debug!("session {id}: authenticate {token}");
-                                        #[derive(Debug, Deserialize)]
-                                        struct UserInfo {
-                                            id: i32,
-                                            name: String,
-                                            language: String,
-                                        }
-                                        let resp: Result<UserInfo> = async {
-                                            Ok(reqwest::Client::new()
-                                                .get(format!("{HOST}/me"))
-                                                .header(
-                                                    reqwest::header::AUTHORIZATION,
-                                                    format!("Bearer {token}"),
-                                                )
-                                                .send()
-                                                .await?
-                                                .error_for_status()?
-                                                .json()
-                                                .await?)
-                                        }
-                                        .await;
+                                        let resp = server.authenticator.authenticate(&token).await;
                                         let resp = match resp {
                                             Ok(resp) => resp,
                                             Err(err) => {
@@ -206,6 +233,9 @@ impl Session {
                                             }
                                         };
                                         debug!("session {id} <- {resp:?}");
+                                        if resp.id == SYSTEM_USER {
+                                            bail!("invalid user id");
+                                        }
                                         let mut users_guard = server.users.write().await;
                                         if let Some(user) = users_guard.get(&resp.id) {
                                             info!("reconnect");
@@ -236,9 +266,11 @@ impl Session {
                                 if let Err(err) = res {
                                     warn!("failed to authenticate: {err:?}");
                                     let _ = send_tx
-                                        .send(ServerCommand::Authenticate(Err(err.to_string())))
+                                        .send(reply(ServerCommand::Authenticate(Err(
+                                            err.to_string()
+                                        ))))
                                         .await;
-                                    panicked.store(true, Ordering::SeqCst);
+                                    lost.store(true, Ordering::SeqCst);
                                     if let Err(err) = server.lost_con_tx.send(id).await {
                                         error!("failed to mark lost connection ({id}): {err:?}");
                                     }
@@ -249,10 +281,10 @@ impl Session {
                                         None => None,
                                     };
                                     let _ = send_tx
-                                        .send(ServerCommand::Authenticate(Ok((
+                                        .send(reply(ServerCommand::Authenticate(Ok((
                                             user.to_info(),
                                             room_state,
-                                        ))))
+                                        )))))
                                         .await;
                                     waiting_for_authenticate.store(false, Ordering::SeqCst);
                                 }
@@ -266,10 +298,10 @@ impl Session {
                         if let Some(resp) = LANGUAGE
                             .scope(Arc::new(user.lang.clone()), process(user, cmd))
                             .await
-                            && let Err(err) = send_tx.send(resp).await
+                            && let Err(err) = send_tx.send(reply(resp)).await
                         {
                             error!("failed to handle message, aborting connection {id}: {err:?}",);
-                            panicked.store(true, Ordering::SeqCst);
+                            lost.store(true, Ordering::SeqCst);
                             if let Err(err) = server.lost_con_tx.send(id).await {
                                 error!("failed to mark lost connection ({id}): {err:?}");
                             }
@@ -279,17 +311,25 @@ impl Session {
             }),
         )
         .await?;
+
+        let user = rx.await?;
+
+        // spawned only now, so that nothing outlives a session that never got
+        // this far
         let monitor_task_handle = tokio::spawn({
             let last_recv = Arc::clone(&last_recv);
+            let lost = Arc::clone(&lost);
+            let timeout = server.config.heartbeat_timeout;
             async move {
                 loop {
                     let recv = *last_recv.lock().await;
-                    time::sleep_until((recv + HEARTBEAT_DISCONNECT_TIMEOUT).into()).await;
+                    time::sleep_until((recv + timeout).into()).await;
 
-                    if *last_recv.lock().await + HEARTBEAT_DISCONNECT_TIMEOUT > Instant::now() {
+                    if *last_recv.lock().await + timeout > Instant::now() {
                         continue;
                     }
 
+                    lost.store(true, Ordering::SeqCst);
                     if let Err(err) = server.lost_con_tx.send(id).await {
                         error!("failed to mark lost connection ({id}): {err:?}");
                     }
@@ -298,13 +338,12 @@ impl Session {
             }
         });
 
-        let user = rx.await?;
-
         let res = Arc::new(Self {
             id,
             stream,
             user,
 
+            lost,
             monitor_task_handle,
         });
         let _ = this.set(Arc::clone(&res));
@@ -312,8 +351,14 @@ impl Session {
         Ok(res)
     }
 
-    pub fn version(&self) -> u8 {
-        self.stream.version()
+    /// Whether the connection has been reported lost, see
+    /// [`ServerState::lost_con_tx`].
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::SeqCst)
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.stream.protocol()
     }
 
     pub fn name(&self) -> &str {
@@ -321,6 +366,20 @@ impl Session {
     }
 
     pub async fn try_send(&self, cmd: ServerCommand) {
+        let cmd = match cmd {
+            ServerCommand::Message(msg) => {
+                let protocol = self.protocol();
+                let downgraded = LANGUAGE.scope(
+                    Arc::new(self.user.lang.clone()),
+                    adapter::downgrade(&protocol, &self.user, msg),
+                );
+                match downgraded.await {
+                    Some(msg) => ServerCommand::Message(msg),
+                    None => return,
+                }
+            }
+            cmd => cmd,
+        };
         if let Err(err) = self.stream.send(cmd).await {
             error!("failed to deliver command to {}: {err:?}", self.id);
         }
@@ -333,6 +392,7 @@ impl Drop for Session {
     }
 }
 
+#[allow(clippy::useless_conversion)]
 async fn process(user: Arc<User>, cmd: ClientCommand) -> Option<ServerCommand> {
     #[inline]
     fn err_to_str<T>(result: Result<T>) -> Result<T, String> {
@@ -372,7 +432,7 @@ async fn process(user: Arc<User>, cmd: ClientCommand) -> Option<ServerCommand> {
         };
     }
     match cmd {
-        ClientCommand::Ping => unreachable!(),
+        ClientCommand::Ping | ClientCommand::Request { .. } => unreachable!(),
         ClientCommand::Authenticate { .. } => Some(ServerCommand::Authenticate(Err(
             "repeated authenticate".to_owned(),
         ))),
@@ -420,7 +480,11 @@ async fn process(user: Arc<User>, cmd: ClientCommand) -> Option<ServerCommand> {
             }
             None
         }
-        ClientCommand::CreateRoom { id } => {
+        ClientCommand::CreateRoom {
+            id,
+            password,
+            private,
+        } => {
             let res: Result<()> = async move {
                 let mut room_guard = user.room.write().await;
                 if room_guard.is_some() {
@@ -428,7 +492,15 @@ async fn process(user: Arc<User>, cmd: ClientCommand) -> Option<ServerCommand> {
                 }
 
                 let mut map_guard = user.server.rooms.write().await;
-                let room = Arc::new(Room::new(id.clone(), Arc::downgrade(&user)));
+                let room = Room::new(
+                    id.clone(),
+                    Arc::downgrade(&user),
+                    &user.server,
+                    password
+                        .map(Varchar::into_inner)
+                        .filter(|it| !it.is_empty()),
+                    private,
+                );
                 match map_guard.entry(id.clone()) {
                     Entry::Vacant(entry) => {
                         entry.insert(Arc::clone(&room));
@@ -441,13 +513,22 @@ async fn process(user: Arc<User>, cmd: ClientCommand) -> Option<ServerCommand> {
                 drop(map_guard);
                 *room_guard = Some(room);
 
-                info!(user = user.id, room = id.to_string(), "user create room");
+                info!(
+                    user = user.id,
+                    room = id.to_string(),
+                    private,
+                    "user create room"
+                );
                 Ok(())
             }
             .await;
             Some(ServerCommand::CreateRoom(err_to_str(res)))
         }
-        ClientCommand::JoinRoom { id, monitor } => {
+        ClientCommand::JoinRoom {
+            id,
+            monitor,
+            password,
+        } => {
             let res: Result<JoinRoomResponse> = async move {
                 let mut room_guard = user.room.write().await;
                 if room_guard.is_some() {
@@ -460,6 +541,15 @@ async fn process(user: Arc<User>, cmd: ClientCommand) -> Option<ServerCommand> {
                 if room.locked.load(Ordering::SeqCst) {
                     bail!(tl!("join-room-locked"));
                 }
+                if room.is_banned(user.id).await {
+                    bail!(tl!("join-banned"));
+                }
+                if !room
+                    .check_password(password.as_ref().map(|it| it.as_str()))
+                    .await
+                {
+                    bail!(tl!("join-wrong-password"));
+                }
                 if !matches!(*room.state.read().await, InternalRoomState::SelectChart) {
                     bail!(tl!("join-game-ongoing"));
                 }
@@ -568,11 +658,7 @@ async fn process(user: Arc<User>, cmd: ClientCommand) -> Option<ServerCommand> {
                 );
                 async move {
                     trace!("fetch");
-                    let res: Chart = reqwest::get(format!("{HOST}/chart/{id}"))
-                        .await?
-                        .error_for_status()?
-                        .json()
-                        .await?;
+                    let res = user.server.charts.chart(id).await?;
                     debug!("chart is {res:?}");
                     room.send(Message::SelectChart {
                         user: user.id,
@@ -601,10 +687,17 @@ async fn process(user: Arc<User>, cmd: ClientCommand) -> Option<ServerCommand> {
                 debug!(room = room.id.to_string(), "room wait for ready");
                 room.reset_game_time().await;
                 room.send(Message::GameStart { user: user.id }).await;
+                let timeout = user.server.config.ready_timeout;
                 *room.state.write().await = InternalRoomState::WaitForReady {
                     started: std::iter::once(user.id).collect::<HashSet<_>>(),
+                    deadline: time::Instant::now() + timeout,
                 };
+                room.start_watchdog(timeout);
                 room.on_state_change().await;
+                room.send(Message::ReadyCountdown {
+                    seconds: timeout.as_secs_f32(),
+                })
+                .await;
                 room.check_all_ready().await;
                 Ok(())
             }
@@ -615,7 +708,7 @@ async fn process(user: Arc<User>, cmd: ClientCommand) -> Option<ServerCommand> {
             let res: Result<()> = async move {
                 get_room!(room);
                 let mut guard = room.state.write().await;
-                if let InternalRoomState::WaitForReady { started } = guard.deref_mut() {
+                if let InternalRoomState::WaitForReady { started, .. } = guard.deref_mut() {
                     if !started.insert(user.id) {
                         bail!("already ready");
                     }
@@ -632,12 +725,13 @@ async fn process(user: Arc<User>, cmd: ClientCommand) -> Option<ServerCommand> {
             let res: Result<()> = async move {
                 get_room!(room);
                 let mut guard = room.state.write().await;
-                if let InternalRoomState::WaitForReady { started } = guard.deref_mut() {
+                if let InternalRoomState::WaitForReady { started, .. } = guard.deref_mut() {
                     if !started.remove(&user.id) {
                         bail!("not ready");
                     }
                     if room.check_host(&user).await.is_ok() {
                         room.send(Message::CancelGame { user: user.id }).await;
+                        room.stop_watchdog();
                         *guard = InternalRoomState::SelectChart;
                         drop(guard);
                         room.on_state_change().await;
@@ -653,61 +747,176 @@ async fn process(user: Arc<User>, cmd: ClientCommand) -> Option<ServerCommand> {
         ClientCommand::Played { id } => {
             let res: Result<()> = async move {
                 get_room!(room);
-                let res: Record = reqwest::get(format!("{HOST}/record/{id}"))
-                    .await?
-                    .error_for_status()?
-                    .json()
-                    .await?;
+                let res = user.server.records.record(id).await?;
                 if res.player != user.id {
                     bail!("invalid record");
                 }
-                debug!(
+                room.on_played(&user, res).await
+            }
+            .await;
+            Some(ServerCommand::Played(err_to_str(res)))
+        }
+        ClientCommand::SubmitRecord { record } => {
+            let res: Result<()> = async move {
+                get_room!(room, InternalRoomState::Playing { .. });
+                let res = user.server.records.submit(user.id, record).await?;
+                room.on_played(&user, res).await
+            }
+            .await;
+            Some(ServerCommand::Played(err_to_str(res)))
+        }
+        ClientCommand::Abort => {
+            let res: Result<()> = async move {
+                get_room!(room);
+                room.abort(&user).await
+            }
+            .await;
+            Some(ServerCommand::Abort(err_to_str(res)))
+        }
+        ClientCommand::ListRooms { page } => {
+            let res: Result<RoomList> = async move {
+                let mut rooms: Vec<_> = user
+                    .server
+                    .rooms
+                    .read()
+                    .await
+                    .values()
+                    .filter(|it| !it.is_private())
+                    .map(Arc::clone)
+                    .collect();
+                rooms.sort_by_key(|it| it.id.to_string());
+                let total = rooms.len() as u32;
+                let mut infos = Vec::new();
+                for room in rooms
+                    .into_iter()
+                    .skip((page as usize).saturating_mul(ROOM_LIST_PAGE_SIZE))
+                    .take(ROOM_LIST_PAGE_SIZE)
+                {
+                    infos.push(room.info().await);
+                }
+                Ok(RoomList {
+                    rooms: infos,
+                    page,
+                    total,
+                })
+            }
+            .await;
+            Some(ServerCommand::ListRooms(err_to_str(res)))
+        }
+        ClientCommand::SetPassword { password } => {
+            let res: Result<()> = async move {
+                get_room!(room, InternalRoomState::SelectChart);
+                room.check_host(&user).await?;
+                let password = password
+                    .map(Varchar::into_inner)
+                    .filter(|it| !it.is_empty());
+                info!(
+                    user = user.id,
                     room = room.id.to_string(),
+                    password = password.is_some(),
+                    "set room password"
+                );
+                room.set_password(password).await;
+                Ok(())
+            }
+            .await;
+            Some(ServerCommand::SetPassword(err_to_str(res)))
+        }
+        ClientCommand::KickUser { user: target } => {
+            let res: Result<()> = async move {
+                get_room!(room);
+                room.check_host(&user).await?;
+                if target == user.id {
+                    bail!("can't kick yourself");
+                }
+                let Some(target) = room.find_user(target).await else {
+                    bail!("user not in room");
+                };
+                info!(
                     user = user.id,
-                    "user played: {res:?}"
+                    room = room.id.to_string(),
+                    target = target.id,
+                    "kick user"
                 );
-                room.send(Message::Played {
-                    user: user.id,
-                    score: res.score,
-                    accuracy: res.accuracy,
-                    full_combo: res.full_combo,
-                })
-                .await;
-                let mut guard = room.state.write().await;
-                if let InternalRoomState::Playing { results, aborted } = guard.deref_mut() {
-                    if aborted.contains(&user.id) {
-                        bail!("aborted");
-                    }
-                    if results.insert(user.id, res).is_some() {
-                        bail!("already uploaded");
-                    }
-                    drop(guard);
-                    room.check_all_ready().await;
+                room.kick(&target, false).await;
+                Ok(())
+            }
+            .await;
+            Some(ServerCommand::KickUser(err_to_str(res)))
+        }
+        ClientCommand::BanUser { user: target } => {
+            let res: Result<()> = async move {
+                get_room!(room);
+                room.check_host(&user).await?;
+                if target == user.id {
+                    bail!("can't ban yourself");
                 }
+                let Some(target) = room.find_user(target).await else {
+                    bail!("user not in room");
+                };
+                info!(
+                    user = user.id,
+                    room = room.id.to_string(),
+                    target = target.id,
+                    "ban user"
+                );
+                room.kick(&target, true).await;
                 Ok(())
             }
             .await;
-            Some(ServerCommand::Played(err_to_str(res)))
+            Some(ServerCommand::BanUser(err_to_str(res)))
         }
-        ClientCommand::Abort => {
+        ClientCommand::TransferHost { user: target } => {
             let res: Result<()> = async move {
                 get_room!(room);
-                let mut guard = room.state.write().await;
-                if let InternalRoomState::Playing { results, aborted } = guard.deref_mut() {
-                    if results.contains_key(&user.id) {
-                        bail!("already uploaded");
-                    }
-                    if !aborted.insert(user.id) {
-                        bail!("aborted");
-                    }
-                    drop(guard);
-                    room.send(Message::Abort { user: user.id }).await;
-                    room.check_all_ready().await;
+                room.check_host(&user).await?;
+                if target == user.id {
+                    bail!("already host");
                 }
+                let Some(target) = room.users().await.into_iter().find(|it| it.id == target) else {
+                    bail!("user not in room");
+                };
+                info!(
+                    user = user.id,
+                    room = room.id.to_string(),
+                    target = target.id,
+                    "transfer host"
+                );
+                room.change_host(&target).await;
                 Ok(())
             }
             .await;
-            Some(ServerCommand::Abort(err_to_str(res)))
+            Some(ServerCommand::TransferHost(err_to_str(res)))
+        }
+        ClientCommand::SetRankingMetric { metric } => {
+            let res: Result<()> = async move {
+                get_room!(room, InternalRoomState::SelectChart);
+                room.check_host(&user).await?;
+                info!(
+                    user = user.id,
+                    room = room.id.to_string(),
+                    "set ranking metric to {metric:?}"
+                );
+                room.set_ranking_metric(metric).await;
+                Ok(())
+            }
+            .await;
+            Some(ServerCommand::SetRankingMetric(err_to_str(res)))
+        }
+        ClientCommand::SetReadyTimeoutAction { action } => {
+            let res: Result<()> = async move {
+                get_room!(room, InternalRoomState::SelectChart);
+                room.check_host(&user).await?;
+                info!(
+                    user = user.id,
+                    room = room.id.to_string(),
+                    "set ready timeout action to {action:?}"
+                );
+                room.set_ready_timeout_action(action).await;
+                Ok(())
+            }
+            .await;
+            Some(ServerCommand::SetReadyTimeoutAction(err_to_str(res)))
         }
     }
 }